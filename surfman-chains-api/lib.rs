@@ -2,15 +2,80 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use euclid::default::Box2D;
+use euclid::default::Size2D;
+
+use std::time::Instant;
+
+/// The identity of a frame, assigned when the producer swaps buffers.
+/// Frame ids increase monotonically for each swap chain.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FrameId(pub u64);
+
+/// The number of times a swap chain has been resized.
+/// All the frames drawn between two resizes share an epoch, and have the same size.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Epoch(pub u64);
+
+/// A frame taken from a swap chain.
+pub struct Frame<Surface, Metadata = ()> {
+    /// The identity of the frame
+    pub id: FrameId,
+    /// The surface the frame was drawn to
+    pub surface: Surface,
+    /// The metadata the producer attached to the frame
+    pub metadata: Metadata,
+    /// The epoch of the swap chain when the frame was drawn
+    pub epoch: Epoch,
+    /// The size of the surface
+    pub size: Size2D<i32>,
+    /// The regions of the surface which changed since the previous frame,
+    /// including any frames which were dropped without being taken.
+    /// Covers the whole surface if the producer did not report any damage.
+    /// Regions are in GL framebuffer coordinates, with the origin at the bottom-left corner,
+    /// so compositors using a top-left origin need to flip them vertically.
+    pub damage: Vec<Box2D<i32>>,
+}
+
 /// The consumer's view of a swap chain
 pub trait SwapChainAPI: 'static + Clone + Send {
     type Surface;
+    type Metadata;
 
     /// Take the current front buffer.
     fn take_surface(&self) -> Option<Self::Surface>;
 
+    /// Take the current front buffer, together with its frame id and metadata.
+    /// Returns `None` if the producer has not swapped buffers since the last frame was taken.
+    fn take_frame(&self) -> Option<Frame<Self::Surface, Self::Metadata>>;
+
+    /// Take the current front buffer, together with the metadata the producer attached to it.
+    /// Returns `None` if the producer has not swapped buffers since the last frame was taken.
+    fn take_surface_with_metadata(&self) -> Option<(Self::Surface, Self::Metadata)> {
+        self.take_frame()
+            .map(|frame| (frame.surface, frame.metadata))
+    }
+
+    /// Take the current front buffer, together with the regions which changed since the previous frame.
+    /// Regions have a bottom-left origin, as described for `Frame::damage`.
+    /// Returns `None` if the producer has not swapped buffers since the last frame was taken.
+    fn take_surface_with_damage(&self) -> Option<(Self::Surface, Vec<Box2D<i32>>)> {
+        self.take_frame().map(|frame| (frame.surface, frame.damage))
+    }
+
     /// Recycle the current front buffer.
     fn recycle_surface(&self, surface: Self::Surface);
+
+    /// Tell the producer that the frame `frame_id` was displayed at `timestamp`.
+    fn present_feedback(&self, frame_id: FrameId, timestamp: Instant);
+
+    /// Ask the producer to resize the swap chain.
+    /// Requests are coalesced, so only the most recent one is acted on.
+    fn request_resize(&self, size: Size2D<i32>);
+
+    /// The current epoch of the swap chain.
+    /// Frames from an earlier epoch were drawn before the most recent resize.
+    fn epoch(&self) -> Epoch;
 }
 
 /// The consumer's view of a collection of swap chains
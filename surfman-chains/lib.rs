@@ -20,6 +20,7 @@
 //! The producer may change the attached swap chain, attaching a currently unattached swap chain,
 //! and detatching the currently attached one.
 
+use euclid::default::Box2D;
 use euclid::default::Size2D;
 
 use fnv::FnvHashMap;
@@ -28,51 +29,1055 @@ use fnv::FnvHashSet;
 use log::debug;
 
 use std::collections::hash_map::Entry;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
 use std::fmt::Debug;
+use std::fmt::Display;
+#[cfg(feature = "async")]
+use std::future::Future;
 use std::hash::Hash;
 use std::mem;
+#[cfg(feature = "async")]
+use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::Condvar;
 use std::sync::Mutex;
 use std::sync::MutexGuard;
 use std::sync::RwLock;
 use std::sync::RwLockReadGuard;
 use std::sync::RwLockWriteGuard;
+#[cfg(feature = "async")]
+use std::task;
+#[cfg(feature = "async")]
+use std::task::Poll;
+#[cfg(feature = "async")]
+use std::task::Waker;
+use std::time::Duration;
+use std::time::Instant;
 
 use sparkle::gl;
+use sparkle::gl::GLbitfield;
+use sparkle::gl::GLenum;
+use sparkle::gl::GLsizei;
+use sparkle::gl::GLsync;
 use sparkle::gl::GLuint;
 use sparkle::gl::Gl;
+use sparkle::gl::GlType;
 
 use surfman::device::Device as DeviceAPI;
+use surfman::ContextAttributeFlags;
+use surfman::ContextAttributes;
 use surfman::ContextID;
-use surfman::Error;
+use surfman::Error as SurfmanError;
 use surfman::SurfaceAccess;
 use surfman::SurfaceInfo;
 use surfman::SurfaceType;
 
+pub use surfman_chains_api::Epoch;
+pub use surfman_chains_api::Frame;
+pub use surfman_chains_api::FrameId;
 pub use surfman_chains_api::SwapChainAPI;
 pub use surfman_chains_api::SwapChainsAPI;
 
+/// An error from a swap chain, with the ids of the swap chain and its producer context if they are known.
+/// Errors can be converted to a `surfman::Error` for compatibility.
+#[derive(Debug)]
+pub struct Error {
+    /// What went wrong
+    pub kind: ErrorKind,
+    /// The id of the producer context of the swap chain
+    pub context_id: Option<ContextID>,
+    /// The id of the swap chain in a `SwapChains` collection, formatted with `Debug`
+    pub swap_chain_id: Option<String>,
+}
+
+/// The kinds of error from a swap chain.
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// A surfman call failed.
+    Surfman(SurfmanError),
+    /// The context is not the producer context for the swap chain.
+    IncompatibleContext,
+    /// A swap chain with the same id is already in the collection.
+    DuplicateSwapChain,
+    /// The size is smaller than (1, 1).
+    InvalidSize(Size2D<i32>),
+    /// The swap chain options are invalid.
+    InvalidOptions,
+    /// The back buffer has been taken, and not replaced.
+    BackBufferTaken,
+    /// A surface was given to replace the back buffer, but the back buffer has not been taken.
+    BackBufferNotTaken,
+    /// The swap chain is attached when it should be detached, or detached when it should be attached.
+    WrongAttachment,
+    /// The swap chain has been destroyed.
+    Destroyed,
+    /// The producer context has no surface bound to it.
+    NoContextSurface,
+    /// The presentation queue is full.
+    QueueFull,
+    /// The swap chain needs the producer's GL bindings, and they were not given.
+    MissingGl,
+    /// The buffers or filter for preserving the back buffer are invalid, or the surfaces do not have the buffers.
+    InvalidPreserveOptions,
+    /// A GL call failed with the given error code.
+    Gl(GLenum),
+    /// The frame with the given id was presented, but preserving its contents
+    /// in the new back buffer failed with the given GL error code.
+    PreserveFailed(FrameId, GLenum),
+    /// The multisampled framebuffer is incomplete, with the given status.
+    IncompleteFramebuffer(GLenum),
+}
+
+impl Error {
+    /// Create an error of the given kind.
+    pub fn new(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            context_id: None,
+            swap_chain_id: None,
+        }
+    }
+
+    // Record the producer context, unless it is already known.
+    fn with_context_id(mut self, context_id: ContextID) -> Error {
+        self.context_id.get_or_insert(context_id);
+        self
+    }
+
+    // Record the swap chain id, unless it is already known.
+    fn with_swap_chain_id<SwapChainID: Debug>(mut self, id: &SwapChainID) -> Error {
+        self.swap_chain_id
+            .get_or_insert_with(|| format!("{:?}", id));
+        self
+    }
+
+    // Record the already formatted swap chain id, if there is one and it is not already known.
+    fn with_formatted_swap_chain_id(mut self, id: Option<&str>) -> Error {
+        if self.swap_chain_id.is_none() {
+            self.swap_chain_id = id.map(String::from);
+        }
+        self
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error::new(kind)
+    }
+}
+
+impl From<SurfmanError> for Error {
+    fn from(err: SurfmanError) -> Error {
+        Error::new(ErrorKind::Surfman(err))
+    }
+}
+
+impl From<Error> for SurfmanError {
+    fn from(err: Error) -> SurfmanError {
+        match err.kind {
+            ErrorKind::Surfman(err) => err,
+            ErrorKind::IncompatibleContext => SurfmanError::IncompatibleContext,
+            _ => SurfmanError::Failed,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ErrorKind::Surfman(ref err) => write!(formatter, "surfman error {:?}", err)?,
+            ErrorKind::IncompatibleContext => write!(formatter, "not the producer context")?,
+            ErrorKind::DuplicateSwapChain => write!(formatter, "duplicate swap chain id")?,
+            ErrorKind::InvalidSize(size) => write!(formatter, "invalid size {:?}", size)?,
+            ErrorKind::InvalidOptions => write!(formatter, "invalid swap chain options")?,
+            ErrorKind::BackBufferTaken => write!(formatter, "back buffer already taken")?,
+            ErrorKind::BackBufferNotTaken => write!(formatter, "back buffer not taken")?,
+            ErrorKind::WrongAttachment => write!(formatter, "swap chain wrongly attached")?,
+            ErrorKind::Destroyed => write!(formatter, "swap chain destroyed")?,
+            ErrorKind::NoContextSurface => write!(formatter, "no surface bound to context")?,
+            ErrorKind::QueueFull => write!(formatter, "presentation queue full")?,
+            ErrorKind::MissingGl => write!(formatter, "missing GL bindings")?,
+            ErrorKind::InvalidPreserveOptions => write!(formatter, "invalid buffers to preserve")?,
+            ErrorKind::Gl(error) => write!(formatter, "GL error {:#x}", error)?,
+            ErrorKind::PreserveFailed(frame_id, error) => write!(
+                formatter,
+                "failed to preserve frame {:?} (GL error {:#x})",
+                frame_id, error
+            )?,
+            ErrorKind::IncompleteFramebuffer(status) => {
+                write!(formatter, "incomplete framebuffer {:#x}", status)?
+            }
+        }
+        if let Some(ref id) = self.swap_chain_id {
+            write!(formatter, " for swap chain {}", id)?;
+        }
+        if let Some(id) = self.context_id {
+            write!(formatter, " for context {:?}", id)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {}
+
 // The data stored for each swap chain.
-struct SwapChainData<Device: DeviceAPI> {
+struct SwapChainData<Device: DeviceAPI, Metadata> {
     // The size of the back buffer
     size: Size2D<i32>,
+    // The number of times the swap chain has been resized
+    epoch: Epoch,
     // The id of the producer context
     context_id: ContextID,
+    // The id of the swap chain, formatted with `Debug`, once it is in a `SwapChains` collection
+    swap_chain_id: Option<String>,
     // The surface access mode for the context.
     surface_access: SurfaceAccess,
+    // The options the swap chain was created with.
+    options: SwapChainOptions,
     // The back buffer of the swap chain.
     back_buffer: BackBuffer<Device>,
-    // Some if the producing context has finished drawing a new front buffer, ready to be displayed.
-    pending_surface: Option<Device::Surface>,
+    // The front buffers the producing context has finished drawing, oldest first, ready to be displayed.
+    pending_surfaces: VecDeque<PendingFrame<Device::Surface, Metadata>>,
     // All of the surfaces that have already been displayed, ready to be recycled.
-    recycled_surfaces: Vec<Device::Surface>,
+    recycled_surfaces: Vec<RecycledSurface<Device>>,
+    // Fences nobody is going to wait for, to be deleted by the producer.
+    stale_fences: Vec<Fence>,
+    // The GL objects left by destroyed swap chains, to be deleted by the producer.
+    gl_garbage: GlGarbage,
+    // The number of frames presented, taken and dropped so far.
+    stats: FrameStats,
+    // The frames taken by a consumer which have not been displayed yet, oldest first,
+    // with the time the producer swapped them.
+    in_flight: VecDeque<(FrameId, Instant)>,
+    // The feedback for the most recently displayed frame.
+    feedback: Option<PresentationFeedback>,
+    // The most recent size requested by a consumer.
+    requested_size: Option<Size2D<i32>>,
+    // The most recent frame drawn to each surface, indexed by surface id.
+    surface_frames: FnvHashMap<usize, FrameId>,
+    // The number of frames since the contents of the back buffer were presented,
+    // or 0 if the back buffer has never been presented.
+    back_buffer_age: u64,
+    // The damage reported for the most recent frames, oldest first.
+    damage_history: VecDeque<FrameDamage>,
+    // Whether the producer context supports framebuffer invalidation, once it is known.
+    invalidation_supported: Option<bool>,
+    // The multisampled render target, once it has been created.
+    multisampled: Option<MultisampledFramebuffer>,
+    // Whether the swap chain has been destroyed.
+    destroyed: bool,
+    // The surfaces recycled after the swap chain was destroyed, to be destroyed by the producer.
+    orphans: Vec<Device::Surface>,
+    // The consumer tasks waiting for the producer to swap buffers.
+    #[cfg(feature = "async")]
+    wakers: Vec<Waker>,
+}
+
+// A front buffer waiting to be taken by a consumer.
+struct PendingFrame<Surface, Metadata> {
+    // The identity of the frame
+    id: FrameId,
+    // The surface the frame was drawn to
+    surface: Surface,
+    // The metadata the producer attached to the frame
+    metadata: Metadata,
+    // Signalled when the producer has finished drawing the frame
+    fence: Option<Fence>,
+    // The time the producer swapped the frame
+    swapped_at: Instant,
+    // The epoch of the swap chain when the frame was drawn
+    epoch: Epoch,
+    // The size of the surface
+    size: Size2D<i32>,
+    // The regions which changed since the previous frame, or `None` if the whole surface changed
+    damage: Option<Vec<Box2D<i32>>>,
+}
+
+// The damage the producer reported for a frame.
+struct FrameDamage {
+    // The identity of the frame
+    id: FrameId,
+    // The size of the surface
+    size: Size2D<i32>,
+    // The union of the damaged regions, or `None` if the whole surface changed
+    region: Option<Box2D<i32>>,
+}
+
+// The maximum number of frames whose damage is remembered.
+// Back buffers older than this are preserved by copying the whole surface.
+const MAX_DAMAGE_HISTORY: usize = 16;
+
+// The maximum number of taken frames waiting for presentation feedback.
+// Consumers which never send feedback shouldn't make the swap chain grow without bound.
+const MAX_IN_FLIGHT_FRAMES: usize = 16;
+
+/// Feedback from a consumer about when a frame was displayed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PresentationFeedback {
+    /// The frame that was displayed
+    pub frame_id: FrameId,
+    /// The time the producer swapped buffers to present the frame
+    pub swapped_at: Instant,
+    /// The time the consumer displayed the frame
+    pub displayed_at: Instant,
+}
+
+impl PresentationFeedback {
+    /// The time between the producer swapping buffers and the consumer displaying the frame.
+    pub fn latency(&self) -> Duration {
+        self.displayed_at.saturating_duration_since(self.swapped_at)
+    }
+}
+
+/// What a swap chain lost when it was repaired by `SwapChain::repair`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RepairReport {
+    /// Whether the back buffer was missing, and a new one was allocated.
+    /// The contents of the old back buffer are lost, and a surface texture still holding it
+    /// will be destroyed if it is returned with `recycle_surface_texture`.
+    pub back_buffer_replaced: bool,
+    /// Whether the swap chain is attached to the producer context.
+    pub attached: bool,
+}
+
+// A surface returned by a consumer.
+struct RecycledSurface<Device: DeviceAPI> {
+    // The surface
+    surface: Device::Surface,
+    // Signalled when the consumer has finished reading the surface
+    fence: Option<Fence>,
+    // True if the surface was allocated in advance, and has never been drawn to
+    prewarmed: bool,
+}
+
+/// A GL fence sync object, signalled once the GPU has executed the commands issued before it.
+/// Fences are used to stop one context reading a surface while another is still writing to it,
+/// so the producer and consumer contexts must be in the same share group.
+#[derive(Debug)]
+pub struct Fence(GLsync);
+
+// Sync objects can be used by any context in the share group.
+unsafe impl Send for Fence {}
+
+impl Fence {
+    /// Insert a fence into the GL command stream, and flush it to the GPU.
+    pub fn insert(gl: &Gl) -> Fence {
+        let sync = gl.fence_sync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0);
+        gl.flush();
+        Fence(sync)
+    }
+
+    /// Make the GL server wait until the fence is signalled, then delete it.
+    pub fn wait(self, gl: &Gl) {
+        gl.wait_sync(self.0, 0, gl::TIMEOUT_IGNORED);
+        gl.delete_sync(self.0);
+    }
+
+    /// Delete the fence without waiting for it.
+    pub fn delete(self, gl: &Gl) {
+        gl.delete_sync(self.0);
+    }
+}
+
+impl<Surface, Metadata> PendingFrame<Surface, Metadata> {
+    fn into_frame(self) -> Frame<Surface, Metadata> {
+        let size = self.size;
+        Frame {
+            id: self.id,
+            surface: self.surface,
+            metadata: self.metadata,
+            epoch: self.epoch,
+            size: self.size,
+            damage: self.damage.unwrap_or_else(|| vec![Box2D::from_size(size)]),
+        }
+    }
+
+    // Separate the surface from the rest of the frame.
+    fn split(self) -> (Surface, PendingFrame<(), Metadata>) {
+        let frame = PendingFrame {
+            id: self.id,
+            surface: (),
+            metadata: self.metadata,
+            fence: self.fence,
+            swapped_at: self.swapped_at,
+            epoch: self.epoch,
+            size: self.size,
+            damage: self.damage,
+        };
+        (self.surface, frame)
+    }
+}
+
+impl<Metadata> PendingFrame<(), Metadata> {
+    // Rejoin a frame with its surface.
+    fn join<Surface>(self, surface: Surface) -> PendingFrame<Surface, Metadata> {
+        PendingFrame {
+            id: self.id,
+            surface,
+            metadata: self.metadata,
+            fence: self.fence,
+            swapped_at: self.swapped_at,
+            epoch: self.epoch,
+            size: self.size,
+            damage: self.damage,
+        }
+    }
+}
+
+// A frame dropped to make room in the queue when swapping buffers, with its position in the queue,
+// and its surface unless that was taken to be the new back buffer.
+type DroppedFrame<Surface, Metadata> = (usize, Option<Surface>, PendingFrame<(), Metadata>);
+
+// Where the new back buffer came from when swapping buffers,
+// so it can be put back if the swap fails.
+enum BackBufferSource {
+    // A recycled surface, with its position in the pool and whether it was pre-warmed
+    Recycled(usize, bool),
+    // The surface of the frame dropped to make room in the queue
+    Dropped,
+    // A newly created surface
+    Created,
+}
+
+// Does the GL implementation support `glInvalidateFramebuffer`?
+// It was added in OpenGL 4.3 and OpenGL ES 3.0.
+fn supports_invalidation(gl: &Gl) -> bool {
+    let mut version = [0, 0];
+    unsafe {
+        gl.get_integer_v(gl::MAJOR_VERSION, &mut version[0..]);
+        gl.get_integer_v(gl::MINOR_VERSION, &mut version[1..]);
+    }
+    // Versions before 3.0 can't be queried this way, and are left as 0.0
+    if version[0] == 0 {
+        let _ = gl.get_error();
+    }
+    let required = match gl.get_type() {
+        GlType::Gl => [4, 3],
+        GlType::Gles => [3, 0],
+    };
+    version >= required
+}
+
+// Combine the damage from two frames, where `None` means the whole surface was damaged.
+fn merge_damage(
+    damage: Option<Vec<Box2D<i32>>>,
+    other: Option<Vec<Box2D<i32>>>,
+) -> Option<Vec<Box2D<i32>>> {
+    let mut damage = damage?;
+    damage.extend(other?);
+    Some(damage)
+}
+
+// Get the options for preserving the back buffer when resizing with `filter`.
+// Depth and stencil buffers are preserved if the surfaces have them and the filter is `NEAREST`.
+fn resize_preserve_options<'a>(
+    gl: &'a Gl,
+    filter: GLenum,
+    attributes: &ContextAttributes,
+) -> Result<PreserveOptions<'a>, Error> {
+    let buffers = match PreserveBuffer::Yes(gl).options(attributes)? {
+        Some(options) if filter == gl::NEAREST => options.buffers,
+        _ => gl::COLOR_BUFFER_BIT,
+    };
+    let options = PreserveOptions {
+        gl,
+        buffers,
+        filter,
+    };
+    PreserveBuffer::With(options).options(attributes)?;
+    Ok(options)
+}
+
+// Copy the contents of the old back buffer to the new one when resizing.
+fn blit_resized(
+    options: &PreserveOptions,
+    anchor: ResizeAnchor,
+    old_fbo: GLuint,
+    old_size: Size2D<i32>,
+    new_info: &SurfaceInfo,
+) -> Result<(), Error> {
+    let (old_width, old_height) = (old_size.width, old_size.height);
+    let (new_width, new_height) = (new_info.size.width, new_info.size.height);
+    // GL framebuffers have their origin at the bottom-left.
+    let (src, dst) = match anchor {
+        ResizeAnchor::TopLeft => {
+            let width = old_width.min(new_width);
+            let height = old_height.min(new_height);
+            (
+                [0, old_height - height, width, old_height],
+                [0, new_height - height, width, new_height],
+            )
+        }
+        ResizeAnchor::Scale => ([0, 0, old_width, old_height], [0, 0, new_width, new_height]),
+    };
+    let gl = options.gl;
+    let _state = GlStateGuard::new(gl);
+    gl.bind_framebuffer(gl::READ_FRAMEBUFFER, old_fbo);
+    gl.bind_framebuffer(gl::DRAW_FRAMEBUFFER, new_info.framebuffer_object);
+    gl.blit_framebuffer(
+        src[0],
+        src[1],
+        src[2],
+        src[3],
+        dst[0],
+        dst[1],
+        dst[2],
+        dst[3],
+        options.buffers,
+        options.filter,
+    );
+    let error = gl.get_error();
+    if error != gl::NO_ERROR {
+        debug!(
+            "Failed to preserve the back buffer when resizing ({:#x})",
+            error
+        );
+        return Err(ErrorKind::Gl(error).into());
+    }
+    Ok(())
+}
+
+/// A frame taken from a swap chain, together with the fence signalled when the producer finished drawing it.
+pub type FencedFrame<Surface, Metadata> = (Frame<Surface, Metadata>, Option<Fence>);
+
+/// The options for swapping the buffers of a swap chain.
+pub struct SwapOptions<'a, Metadata = ()> {
+    /// Whether to copy the new front buffer to the new back buffer.
+    pub preserve_buffer: PreserveBuffer<'a>,
+    /// The metadata to attach to the new front buffer.
+    pub metadata: Metadata,
+    /// The regions of the new front buffer which changed since the previous frame,
+    /// or `None` if the whole surface may have changed.
+    /// Regions are in GL framebuffer coordinates, with the origin at the bottom-left corner.
+    pub damage: Option<Vec<Box2D<i32>>>,
+    /// The GL bindings for the producer context.
+    /// Required if the swap chain uses fence synchronization.
+    pub gl: Option<&'a Gl>,
+}
+
+impl<'a, Metadata: Default> Default for SwapOptions<'a, Metadata> {
+    fn default() -> SwapOptions<'a, Metadata> {
+        SwapOptions {
+            preserve_buffer: PreserveBuffer::No,
+            metadata: Metadata::default(),
+            damage: None,
+            gl: None,
+        }
+    }
+}
+
+/// Counts of the frames that have passed through a swap chain.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FrameStats {
+    /// The number of frames the producer has presented by swapping buffers.
+    pub presented: u64,
+    /// The number of presented frames taken by a consumer.
+    pub taken: u64,
+    /// The number of presented frames recycled without being taken by a consumer.
+    pub dropped: u64,
+}
+
+/// The options for creating a swap chain.
+#[derive(Clone, Debug)]
+pub struct SwapChainOptions {
+    /// The maximum number of front buffers waiting to be taken by the consumer.
+    /// A depth of 1 gives a double-buffered swap chain, a depth of 2 a triple-buffered one.
+    /// Must be at least 1.
+    pub queue_depth: usize,
+    /// What happens when the producer swaps buffers and the queue is full.
+    pub present_mode: PresentMode,
+    /// Whether to synchronize the producer and consumers with GL fences.
+    /// Each front buffer is fenced when the producer swaps buffers,
+    /// and consumers can fence the surfaces they recycle.
+    /// The producer must pass its GL bindings in `SwapOptions` when swapping buffers.
+    /// Consumers must take front buffers with `take_frame_with_fence`, `take_frame_with_fence_timeout`,
+    /// `wait_for_frame_with_fence` or `next_frame_with_fence`,
+    /// since the other ways of taking them discard the fence.
+    /// Fences that are still outstanding when the swap chain is destroyed
+    /// are left to be deleted along with the producer context.
+    pub fence_sync: bool,
+    /// The maximum number of recycled surfaces kept for reuse as back buffers.
+    /// With the default of 0, every unused surface is destroyed when the producer swaps buffers.
+    pub pool_capacity: usize,
+    /// Which recycled surfaces are destroyed when the pool is trimmed.
+    pub eviction_policy: EvictionPolicy,
+    /// The number of spare surfaces to allocate when the swap chain is created or resized,
+    /// so the first swaps after that do not allocate.
+    /// The pool is never trimmed below this number of surfaces.
+    pub prewarmed_surfaces: usize,
+    /// Whether swapping buffers applies the most recent resize requested by a consumer.
+    /// The new size takes effect from the new back buffer, so the front buffer being presented
+    /// keeps its size. The new back buffer is taken from the pool if it has a surface of the
+    /// requested size, and is allocated otherwise.
+    /// Otherwise the producer can check for requests with `pending_resize`.
+    pub resize_on_swap: bool,
+    /// Whether to invalidate the new back buffer when buffers are swapped without preserving it,
+    /// so tile-based GPUs do not load its old contents.
+    /// The producer must pass its GL bindings in `SwapOptions` when swapping buffers.
+    /// Has no effect on GL versions without `glInvalidateFramebuffer`.
+    /// The age of an invalidated back buffer is 0, since its contents are undefined.
+    pub invalidate_back_buffer: bool,
+    /// The number of samples in the multisampled render target, or 0 for no multisampling.
+    /// The producer draws to the framebuffer returned by `multisampled_framebuffer`,
+    /// which is resolved into the back buffer when buffers are swapped,
+    /// so the producer must pass its GL bindings in `SwapOptions`.
+    /// The number of samples is clamped to the maximum supported by the producer context.
+    pub samples: GLsizei,
+}
+
+impl Default for SwapChainOptions {
+    fn default() -> SwapChainOptions {
+        SwapChainOptions {
+            queue_depth: 1,
+            present_mode: PresentMode::Mailbox,
+            fence_sync: false,
+            pool_capacity: 0,
+            eviction_policy: EvictionPolicy::MatchingSize,
+            prewarmed_surfaces: 0,
+            resize_on_swap: false,
+            invalidate_back_buffer: false,
+            samples: 0,
+        }
+    }
+}
+
+impl SwapChainOptions {
+    // Returns `Ok` if these options describe a valid swap chain.
+    fn validate(&self) -> Result<(), Error> {
+        if (self.queue_depth < 1) || (self.samples < 0) {
+            return Err(ErrorKind::InvalidOptions.into());
+        }
+        Ok(())
+    }
+}
+
+/// The presentation policy of a swap chain.
+///
+/// In every mode, `take_surface` and `take_pending_surface` return the oldest front buffer
+/// in the queue. If the queue is empty, `take_pending_surface` returns `None`,
+/// and `take_surface` returns the most recently recycled surface, so the consumer
+/// can redisplay the last frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PresentMode {
+    /// If the queue is full, `swap_buffers` recycles the oldest front buffer
+    /// to make room for the new one. The producer never waits for the consumer,
+    /// but the consumer may not see every frame.
+    Mailbox,
+    /// If the queue is full, `swap_buffers` returns an error and leaves the swap chain unchanged.
+    /// The consumer sees every frame, and the producer must wait for the consumer
+    /// to take a front buffer before swapping again, for example with `swap_buffers_timeout`.
+    Fifo,
+    /// If the queue is full, `swap_buffers` recycles the newest front buffer
+    /// and replaces it with the new one. The consumer sees the queued frames in order
+    /// and the producer never waits, but frames produced while the queue is full may be skipped.
+    FifoRelaxed,
+}
+
+/// The policy for destroying recycled surfaces.
+/// The pool is trimmed each time the producer swaps buffers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    /// When the pool overflows, destroy the surfaces that were recycled least recently.
+    /// Surfaces which do not match the size of the swap chain are kept until they are evicted,
+    /// which suits producers that switch back and forth between sizes.
+    OldestFirst,
+    /// Destroy the surfaces which do not match the size of the swap chain,
+    /// and when the pool overflows, destroy the surfaces that were recycled least recently.
+    MatchingSize,
+}
+
+/// Where the old contents of a swap chain end up when it is resized by `resize_preserving`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResizeAnchor {
+    /// Copy the contents at their original size, keeping the top-left corner in place.
+    /// Contents outside the new size are cropped.
+    TopLeft,
+    /// Scale the contents to fill the new size.
+    Scale,
+}
+
+/// The options for clearing the back buffer of a swap chain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClearOptions {
+    /// The value to clear the color buffer to, or `None` to leave it unchanged.
+    pub color: Option<[f32; 4]>,
+    /// The value to clear the depth buffer to, or `None` to leave it unchanged.
+    pub depth: Option<f64>,
+    /// The value to clear the stencil buffer to, or `None` to leave it unchanged.
+    pub stencil: Option<i32>,
+    /// The region to clear, or `None` to clear the whole surface.
+    pub scissor: Option<Box2D<i32>>,
+}
+
+impl Default for ClearOptions {
+    fn default() -> ClearOptions {
+        ClearOptions {
+            color: Some([0., 0., 0., 0.]),
+            depth: Some(1.),
+            stencil: Some(0),
+            scissor: None,
+        }
+    }
+}
+
+impl ClearOptions {
+    // Set up the GL state for clearing, and return the buffers to clear.
+    fn apply(&self, gl: &Gl) -> GLbitfield {
+        let mut buffers = 0;
+        if let Some(color) = self.color {
+            gl.clear_color(color[0], color[1], color[2], color[3]);
+            gl.color_mask(true, true, true, true);
+            buffers |= gl::COLOR_BUFFER_BIT;
+        }
+        if let Some(depth) = self.depth {
+            gl.clear_depth(depth);
+            gl.depth_mask(true);
+            buffers |= gl::DEPTH_BUFFER_BIT;
+        }
+        if let Some(stencil) = self.stencil {
+            gl.clear_stencil(stencil);
+            gl.stencil_mask(0xFFFFFFFF);
+            buffers |= gl::STENCIL_BUFFER_BIT;
+        }
+        if let Some(scissor) = self.scissor {
+            gl.enable(gl::SCISSOR_TEST);
+            gl.scissor(
+                scissor.min.x,
+                scissor.min.y,
+                scissor.width(),
+                scissor.height(),
+            );
+        }
+        buffers
+    }
+}
+
+// The multisampled render targets left by destroyed swap chains, with their producer contexts,
+// to be deleted by the next producer call with GL bindings for the same context.
+// Shared by all the swap chains in a `SwapChains` collection.
+type GlGarbage = Arc<Mutex<Vec<(ContextID, MultisampledFramebuffer)>>>;
+
+// A multisampled render target, which is resolved into the back buffer when buffers are swapped.
+struct MultisampledFramebuffer {
+    // The framebuffer object
+    framebuffer: GLuint,
+    // The renderbuffers attached to the framebuffer, with their internal formats
+    renderbuffers: Vec<(GLuint, GLenum)>,
+    // The size of the renderbuffers
+    size: Size2D<i32>,
+    // The number of samples in the renderbuffers
+    samples: GLsizei,
+}
+
+impl MultisampledFramebuffer {
+    // Create a render target with the attachments of the surfaces created by the context.
+    fn new(
+        gl: &Gl,
+        size: Size2D<i32>,
+        samples: GLsizei,
+        attributes: &ContextAttributes,
+    ) -> Result<MultisampledFramebuffer, Error> {
+        let mut max_samples = [0];
+        unsafe {
+            gl.get_integer_v(gl::MAX_SAMPLES, &mut max_samples[..]);
+        }
+        // Surfaces always have an alpha channel, and resolving needs the formats to match
+        let mut attachments = vec![(gl::COLOR_ATTACHMENT0, gl::RGBA8)];
+        let depth = attributes.flags.contains(ContextAttributeFlags::DEPTH);
+        let stencil = attributes.flags.contains(ContextAttributeFlags::STENCIL);
+        match (depth, stencil) {
+            (true, true) => attachments.push((gl::DEPTH_STENCIL_ATTACHMENT, gl::DEPTH24_STENCIL8)),
+            (true, false) => attachments.push((gl::DEPTH_ATTACHMENT, gl::DEPTH_COMPONENT24)),
+            (false, true) => attachments.push((gl::STENCIL_ATTACHMENT, gl::STENCIL_INDEX8)),
+            (false, false) => (),
+        }
+
+        let framebuffer = gl.gen_framebuffers(1)[0];
+        let names = gl.gen_renderbuffers(attachments.len() as GLsizei);
+        let mut multisampled = MultisampledFramebuffer {
+            framebuffer,
+            renderbuffers: names
+                .iter()
+                .zip(&attachments)
+                .map(|(&name, &(_, format))| (name, format))
+                .collect(),
+            size,
+            samples: samples.min(max_samples[0]),
+        };
+        multisampled.allocate(gl, size);
+
+        let _state = GlStateGuard::new(gl);
+        gl.bind_framebuffer(gl::FRAMEBUFFER, framebuffer);
+        for (&name, &(attachment, _)) in names.iter().zip(&attachments) {
+            gl.framebuffer_renderbuffer(gl::FRAMEBUFFER, attachment, gl::RENDERBUFFER, name);
+        }
+        let status = gl.check_framebuffer_status(gl::FRAMEBUFFER);
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            debug!("Incomplete multisampled framebuffer ({:#x})", status);
+            multisampled.delete(gl);
+            return Err(ErrorKind::IncompleteFramebuffer(status).into());
+        }
+        Ok(multisampled)
+    }
+
+    // Allocate storage for the renderbuffers, discarding their contents.
+    fn allocate(&mut self, gl: &Gl, size: Size2D<i32>) {
+        let mut bound_renderbuffer = [0];
+        unsafe {
+            gl.get_integer_v(gl::RENDERBUFFER_BINDING, &mut bound_renderbuffer[..]);
+        }
+        for &(name, format) in &self.renderbuffers {
+            gl.bind_renderbuffer(gl::RENDERBUFFER, name);
+            gl.renderbuffer_storage_multisample(
+                gl::RENDERBUFFER,
+                self.samples,
+                format,
+                size.width,
+                size.height,
+            );
+        }
+        gl.bind_renderbuffer(gl::RENDERBUFFER, bound_renderbuffer[0] as GLuint);
+        self.size = size;
+    }
+
+    // Resolve the color buffer into the framebuffer `fbo` of the given size.
+    fn resolve(&self, gl: &Gl, fbo: GLuint, size: Size2D<i32>) -> Result<(), Error> {
+        let width = self.size.width.min(size.width);
+        let height = self.size.height.min(size.height);
+        let _state = GlStateGuard::new(gl);
+        gl.bind_framebuffer(gl::READ_FRAMEBUFFER, self.framebuffer);
+        gl.bind_framebuffer(gl::DRAW_FRAMEBUFFER, fbo);
+        gl.blit_framebuffer(
+            0,
+            0,
+            width,
+            height,
+            0,
+            0,
+            width,
+            height,
+            gl::COLOR_BUFFER_BIT,
+            gl::NEAREST,
+        );
+        let error = gl.get_error();
+        if error != gl::NO_ERROR {
+            debug!("Failed to resolve multisampled framebuffer ({:#x})", error);
+            return Err(ErrorKind::Gl(error).into());
+        }
+        Ok(())
+    }
+
+    // Copy the single-sampled framebuffer `fbo` into the render target, replicating each sample.
+    // This is not supported by OpenGL ES.
+    fn fill(&self, gl: &Gl, fbo: GLuint, buffers: GLbitfield) -> Result<(), Error> {
+        let _state = GlStateGuard::new(gl);
+        gl.bind_framebuffer(gl::READ_FRAMEBUFFER, fbo);
+        gl.bind_framebuffer(gl::DRAW_FRAMEBUFFER, self.framebuffer);
+        gl.blit_framebuffer(
+            0,
+            0,
+            self.size.width,
+            self.size.height,
+            0,
+            0,
+            self.size.width,
+            self.size.height,
+            buffers,
+            gl::NEAREST,
+        );
+        let error = gl.get_error();
+        if error != gl::NO_ERROR {
+            debug!("Failed to fill multisampled framebuffer ({:#x})", error);
+            return Err(ErrorKind::Gl(error).into());
+        }
+        Ok(())
+    }
+
+    // Delete the GL objects.
+    fn delete(self, gl: &Gl) {
+        let names: Vec<GLuint> = self.renderbuffers.iter().map(|&(name, _)| name).collect();
+        gl.delete_framebuffers(&[self.framebuffer]);
+        gl.delete_renderbuffers(&names);
+    }
 }
 
 pub enum PreserveBuffer<'a> {
+    /// Preserve the color buffer, and the depth and stencil buffers if the context has them.
     Yes(&'a Gl),
+    /// Preserve the buffers selected by the options.
+    With(PreserveOptions<'a>),
     No,
 }
 
+impl<'a> PreserveBuffer<'a> {
+    // Get the options for preserving the back buffer,
+    // checked against the attachments of the surfaces created by the context.
+    // Returns `None` if the back buffer is not preserved.
+    fn options(
+        &self,
+        attributes: &ContextAttributes,
+    ) -> Result<Option<PreserveOptions<'a>>, Error> {
+        let options = match *self {
+            PreserveBuffer::No => return Ok(None),
+            PreserveBuffer::Yes(gl) => {
+                let mut options = PreserveOptions::new(gl);
+                if attributes.flags.contains(ContextAttributeFlags::DEPTH) {
+                    options.buffers |= gl::DEPTH_BUFFER_BIT;
+                }
+                if attributes.flags.contains(ContextAttributeFlags::STENCIL) {
+                    options.buffers |= gl::STENCIL_BUFFER_BIT;
+                }
+                return Ok(Some(options));
+            }
+            PreserveBuffer::With(options) => options,
+        };
+        let all_buffers = gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT;
+        if (options.buffers == 0) || (options.buffers & !all_buffers != 0) {
+            debug!("Invalid buffers {:#x} to preserve", options.buffers);
+            return Err(ErrorKind::InvalidPreserveOptions.into());
+        }
+        if (options.filter != gl::NEAREST) && (options.filter != gl::LINEAR) {
+            debug!("Invalid filter {:#x} to preserve buffers", options.filter);
+            return Err(ErrorKind::InvalidPreserveOptions.into());
+        }
+        let depth_stencil = gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT;
+        if (options.filter != gl::NEAREST) && (options.buffers & depth_stencil != 0) {
+            debug!("Depth and stencil buffers can only be preserved with NEAREST filtering");
+            return Err(ErrorKind::InvalidPreserveOptions.into());
+        }
+        if (options.buffers & gl::DEPTH_BUFFER_BIT != 0)
+            && !attributes.flags.contains(ContextAttributeFlags::DEPTH)
+        {
+            debug!("Cannot preserve the depth buffer of surfaces without one");
+            return Err(ErrorKind::InvalidPreserveOptions.into());
+        }
+        if (options.buffers & gl::STENCIL_BUFFER_BIT != 0)
+            && !attributes.flags.contains(ContextAttributeFlags::STENCIL)
+        {
+            debug!("Cannot preserve the stencil buffer of surfaces without one");
+            return Err(ErrorKind::InvalidPreserveOptions.into());
+        }
+        Ok(Some(options))
+    }
+}
+
+/// The options for preserving the contents of the back buffer when buffers are swapped.
+#[derive(Clone, Copy)]
+pub struct PreserveOptions<'a> {
+    /// The GL bindings for the producer context.
+    pub gl: &'a Gl,
+    /// The buffers to preserve, a combination of
+    /// `gl::COLOR_BUFFER_BIT`, `gl::DEPTH_BUFFER_BIT` and `gl::STENCIL_BUFFER_BIT`.
+    /// The surfaces must have each of the buffers.
+    pub buffers: GLbitfield,
+    /// The filter used if the buffers are different sizes, either `gl::NEAREST` or `gl::LINEAR`.
+    /// Depth and stencil buffers can only be preserved with `gl::NEAREST`.
+    pub filter: GLenum,
+}
+
+impl<'a> PreserveOptions<'a> {
+    /// Options which preserve just the color buffer, using `gl::NEAREST` filtering.
+    pub fn new(gl: &'a Gl) -> PreserveOptions<'a> {
+        PreserveOptions {
+            gl,
+            buffers: gl::COLOR_BUFFER_BIT,
+            filter: gl::NEAREST,
+        }
+    }
+}
+
+// The GL state which swap chains change when blitting or clearing surfaces.
+// The state is saved when the guard is created, and restored when it is dropped,
+// so the producer's GL state is left undisturbed even if an operation fails part way through.
+struct GlStateGuard<'a> {
+    gl: &'a Gl,
+    bound_fbos: [i32; 2],
+    clear_color: [f32; 4],
+    clear_depth: [f32; 1],
+    clear_stencil: [i32; 1],
+    color_mask: [u8; 4],
+    depth_mask: [u8; 1],
+    stencil_mask: [i32; 1],
+    scissor_box: [i32; 4],
+    scissor_enabled: bool,
+    rasterizer_enabled: bool,
+}
+
+impl<'a> GlStateGuard<'a> {
+    // Save the current GL state, and disable the state which affects blits and clears.
+    fn new(gl: &'a Gl) -> GlStateGuard<'a> {
+        let mut state = GlStateGuard {
+            gl,
+            bound_fbos: [0, 0],
+            clear_color: [0., 0., 0., 0.],
+            clear_depth: [0.],
+            clear_stencil: [0],
+            color_mask: [0, 0, 0, 0],
+            depth_mask: [0],
+            stencil_mask: [0],
+            scissor_box: [0, 0, 0, 0],
+            scissor_enabled: gl.is_enabled(gl::SCISSOR_TEST),
+            rasterizer_enabled: gl.is_enabled(gl::RASTERIZER_DISCARD),
+        };
+        unsafe {
+            gl.get_integer_v(gl::DRAW_FRAMEBUFFER_BINDING, &mut state.bound_fbos[0..]);
+            gl.get_integer_v(gl::READ_FRAMEBUFFER_BINDING, &mut state.bound_fbos[1..]);
+            gl.get_float_v(gl::COLOR_CLEAR_VALUE, &mut state.clear_color[..]);
+            gl.get_float_v(gl::DEPTH_CLEAR_VALUE, &mut state.clear_depth[..]);
+            gl.get_integer_v(gl::STENCIL_CLEAR_VALUE, &mut state.clear_stencil[..]);
+            gl.get_boolean_v(gl::DEPTH_WRITEMASK, &mut state.depth_mask[..]);
+            gl.get_integer_v(gl::STENCIL_WRITEMASK, &mut state.stencil_mask[..]);
+            gl.get_boolean_v(gl::COLOR_WRITEMASK, &mut state.color_mask[..]);
+            gl.get_integer_v(gl::SCISSOR_BOX, &mut state.scissor_box[..]);
+        }
+        gl.disable(gl::SCISSOR_TEST);
+        gl.disable(gl::RASTERIZER_DISCARD);
+        state
+    }
+}
+
+impl<'a> Drop for GlStateGuard<'a> {
+    // Restore the saved GL state.
+    fn drop(&mut self) {
+        let gl = self.gl;
+        gl.bind_framebuffer(gl::DRAW_FRAMEBUFFER, self.bound_fbos[0] as GLuint);
+        gl.bind_framebuffer(gl::READ_FRAMEBUFFER, self.bound_fbos[1] as GLuint);
+        gl.clear_color(
+            self.clear_color[0],
+            self.clear_color[1],
+            self.clear_color[2],
+            self.clear_color[3],
+        );
+        gl.color_mask(
+            self.color_mask[0] != 0,
+            self.color_mask[1] != 0,
+            self.color_mask[2] != 0,
+            self.color_mask[3] != 0,
+        );
+        gl.clear_depth(self.clear_depth[0] as f64);
+        gl.clear_stencil(self.clear_stencil[0]);
+        gl.depth_mask(self.depth_mask[0] != 0);
+        gl.stencil_mask(self.stencil_mask[0] as GLuint);
+        gl.scissor(
+            self.scissor_box[0],
+            self.scissor_box[1],
+            self.scissor_box[2],
+            self.scissor_box[3],
+        );
+        // Scissoring may have been enabled since the state was saved
+        if self.scissor_enabled {
+            gl.enable(gl::SCISSOR_TEST);
+        } else {
+            gl.disable(gl::SCISSOR_TEST);
+        }
+        if self.rasterizer_enabled {
+            gl.enable(gl::RASTERIZER_DISCARD);
+        }
+    }
+}
+
 enum BackBuffer<Device: DeviceAPI> {
     Attached,
     Detached(Device::Surface),
@@ -86,16 +1091,17 @@ impl<Device: DeviceAPI> BackBuffer<Device> {
         device: &Device,
         context: &mut Device::Context,
     ) -> Result<Device::Surface, Error> {
-        let new_back_buffer = match self {
-            BackBuffer::Attached => BackBuffer::TakenAttached,
-            BackBuffer::Detached(_) => BackBuffer::TakenDetached,
-            _ => return Err(Error::Failed),
-        };
-        let surface = match mem::replace(self, new_back_buffer) {
-            BackBuffer::Attached => device.unbind_surface_from_context(context)?.unwrap(),
-            BackBuffer::Detached(surface) => surface,
-            _ => unreachable!(),
+        let surface = match self {
+            BackBuffer::Attached => device
+                .unbind_surface_from_context(context)?
+                .ok_or(ErrorKind::NoContextSurface)?,
+            BackBuffer::Detached(_) => match mem::replace(self, BackBuffer::TakenDetached) {
+                BackBuffer::Detached(surface) => return Ok(surface),
+                _ => unreachable!(),
+            },
+            _ => return Err(ErrorKind::BackBufferTaken.into()),
         };
+        *self = BackBuffer::TakenAttached;
         Ok(surface)
     }
     fn take_surface_texture(
@@ -108,7 +1114,7 @@ impl<Device: DeviceAPI> BackBuffer<Device> {
             .create_surface_texture(context, surface)
             .map_err(|(err, surface)| {
                 let _ = self.replace_surface(device, context, surface);
-                err
+                err.into()
             })
     }
     fn replace_surface(
@@ -117,129 +1123,816 @@ impl<Device: DeviceAPI> BackBuffer<Device> {
         context: &mut Device::Context,
         surface: Device::Surface,
     ) -> Result<(), Error> {
+        self.try_replace_surface(device, context, surface)
+            .map_err(|(err, mut surface)| {
+                debug!("Oh no, destroying surface");
+                let _ = device.destroy_surface(context, &mut surface);
+                err
+            })
+    }
+    fn try_replace_surface(
+        &mut self,
+        device: &Device,
+        context: &mut Device::Context,
+        surface: Device::Surface,
+    ) -> Result<(), (Error, Device::Surface)> {
         let new_back_buffer = match self {
             BackBuffer::TakenAttached => {
-                if let Err((err, mut surface)) = device.bind_surface_to_context(context, surface) {
-                    debug!("Oh no, destroying surface");
-                    let _ = device.destroy_surface(context, &mut surface);
-                    return Err(err);
-                }
+                device
+                    .bind_surface_to_context(context, surface)
+                    .map_err(|(err, surface)| (Error::from(err), surface))?;
                 BackBuffer::Attached
             }
             BackBuffer::TakenDetached => BackBuffer::Detached(surface),
-            _ => return Err(Error::Failed),
+            _ => return Err((ErrorKind::BackBufferNotTaken.into(), surface)),
+        };
+        *self = new_back_buffer;
+        Ok(())
+    }
+    fn replace_surface_texture(
+        &mut self,
+        device: &Device,
+        context: &mut Device::Context,
+        surface_texture: Device::SurfaceTexture,
+    ) -> Result<(), Error> {
+        let surface = device
+            .destroy_surface_texture(context, surface_texture)
+            .map_err(|(err, _)| err)?;
+        self.replace_surface(device, context, surface)
+    }
+    // Run `op` with the framebuffer object of the back buffer.
+    // The framebuffer object of a detached surface is only valid while it is bound to the context,
+    // and some backends (such as ANGLE) report 0 otherwise. In that case the back buffer is bound
+    // in place of the context's surface while `op` runs.
+    fn with_framebuffer<T>(
+        &mut self,
+        device: &Device,
+        context: &mut Device::Context,
+        op: impl FnOnce(GLuint) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        match self {
+            BackBuffer::Attached => {
+                let info = device
+                    .context_surface_info(context)?
+                    .ok_or(ErrorKind::NoContextSurface)?;
+                return op(info.framebuffer_object);
+            }
+            BackBuffer::Detached(surface) => {
+                let fbo = device.surface_info(surface).framebuffer_object;
+                if fbo != 0 {
+                    return op(fbo);
+                }
+            }
+            _ => return Err(ErrorKind::BackBufferTaken.into()),
+        }
+
+        // Make the back buffer the current surface
+        let surface = self.take_surface(device, context)?;
+        let attached = match device.unbind_surface_from_context(context) {
+            Ok(attached) => attached,
+            Err(err) => {
+                self.replace_surface(device, context, surface)?;
+                return Err(err.into());
+            }
+        };
+        if let Err((err, surface)) = device.bind_surface_to_context(context, surface) {
+            self.replace_surface(device, context, surface)?;
+            BackBuffer::<Device>::reattach(device, context, attached)?;
+            return Err(err.into());
+        }
+        let result = match device.context_surface_info(context) {
+            Ok(Some(info)) => op(info.framebuffer_object),
+            Ok(None) => Err(ErrorKind::NoContextSurface.into()),
+            Err(err) => Err(err.into()),
+        };
+
+        // Reattach the old surface
+        let surface = match device.unbind_surface_from_context(context) {
+            Ok(Some(surface)) => surface,
+            unbound => {
+                // The back buffer is left taken, so the swap chain can be repaired
+                debug!("Oh no, destroying surface");
+                if let Some(mut attached) = attached {
+                    let _ = device.destroy_surface(context, &mut attached);
+                }
+                return Err(match unbound {
+                    Err(err) => err.into(),
+                    _ => ErrorKind::NoContextSurface.into(),
+                });
+            }
+        };
+        self.replace_surface(device, context, surface)?;
+        BackBuffer::<Device>::reattach(device, context, attached)?;
+        result
+    }
+    // Bind `surface` to the context, if there is one.
+    fn reattach(
+        device: &Device,
+        context: &mut Device::Context,
+        surface: Option<Device::Surface>,
+    ) -> Result<(), Error> {
+        if let Some(surface) = surface {
+            if let Err((err, mut surface)) = device.bind_surface_to_context(context, surface) {
+                debug!("Oh no, destroying surface");
+                let _ = device.destroy_surface(context, &mut surface);
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<Device: DeviceAPI, Metadata> SwapChainData<Device, Metadata> {
+    fn new(
+        size: Size2D<i32>,
+        context_id: ContextID,
+        surface_access: SurfaceAccess,
+        options: SwapChainOptions,
+        back_buffer: BackBuffer<Device>,
+    ) -> SwapChainData<Device, Metadata> {
+        SwapChainData {
+            size,
+            epoch: Epoch(0),
+            context_id,
+            swap_chain_id: None,
+            surface_access,
+            options,
+            back_buffer,
+            pending_surfaces: VecDeque::new(),
+            recycled_surfaces: Vec::new(),
+            stale_fences: Vec::new(),
+            gl_garbage: GlGarbage::default(),
+            stats: FrameStats::default(),
+            in_flight: VecDeque::new(),
+            feedback: None,
+            requested_size: None,
+            surface_frames: FnvHashMap::default(),
+            back_buffer_age: 0,
+            damage_history: VecDeque::new(),
+            invalidation_supported: None,
+            multisampled: None,
+            destroyed: false,
+            orphans: Vec::new(),
+            #[cfg(feature = "async")]
+            wakers: Vec::new(),
+        }
+    }
+
+    // Create the data for an attached swap chain, using the context's current surface as the back buffer.
+    // Returns an error if the context has no surface.
+    fn create_attached(
+        device: &mut Device,
+        context: &mut Device::Context,
+        surface_access: SurfaceAccess,
+        options: SwapChainOptions,
+    ) -> Result<SwapChainData<Device, Metadata>, Error> {
+        options.validate()?;
+        let size = device
+            .context_surface_info(context)?
+            .ok_or(ErrorKind::NoContextSurface)?
+            .size;
+        let mut data = SwapChainData::new(
+            size,
+            device.context_id(context),
+            surface_access,
+            options,
+            BackBuffer::Attached,
+        );
+        if let Err(err) = data.prewarm(device, context) {
+            let _ = data.destroy_pool(device, context);
+            return Err(err);
+        }
+        Ok(data)
+    }
+
+    // Create the data for a detached swap chain, with a new back buffer.
+    fn create_detached(
+        device: &mut Device,
+        context: &mut Device::Context,
+        surface_access: SurfaceAccess,
+        size: Size2D<i32>,
+        options: SwapChainOptions,
+    ) -> Result<SwapChainData<Device, Metadata>, Error> {
+        options.validate()?;
+        let surface_type = SurfaceType::Generic { size };
+        let surface = device.create_surface(context, surface_access, surface_type)?;
+        let mut data = SwapChainData::new(
+            size,
+            device.context_id(context),
+            surface_access,
+            options,
+            BackBuffer::Detached(surface),
+        );
+        if let Err(err) = data.prewarm(device, context) {
+            let _ = data.destroy(device, context, None);
+            return Err(err);
+        }
+        Ok(data)
+    }
+
+    // Returns `Ok` if `context` is the producer context for this swap chain.
+    fn validate_context(&self, device: &Device, context: &Device::Context) -> Result<(), Error> {
+        if self.context_id != device.context_id(context) {
+            Err(ErrorKind::IncompatibleContext.into())
+        } else if self.destroyed {
+            Err(ErrorKind::Destroyed.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Swap the back and front buffers.
+    // Called by the producer.
+    // Returns an error if `context` is not the producer context for this swap chain.
+    // Returns an error if the present mode is `Fifo` and the queue is full.
+    // Returns an error if the swap chain uses fence synchronization, invalidation or multisampling
+    // and no GL bindings are given.
+    // Returns an error if the multisampled render target cannot be resolved.
+    // Returns an error if the buffers to preserve are invalid, or do not match the surfaces.
+    // Returns a `PreserveFailed` error if preserving the back buffer fails,
+    // in which case the buffers are still swapped.
+    fn swap_buffers(
+        &mut self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        options: SwapOptions<'_, Metadata>,
+    ) -> Result<FrameId, Error> {
+        debug!("Swap buffers on context {:?}", self.context_id);
+        self.validate_context(device, context)?;
+        let needs_gl = self.options.fence_sync
+            || self.options.invalidate_back_buffer
+            || self.multisampled.is_some();
+        if needs_gl && options.gl.is_none() {
+            debug!("Missing GL bindings for context {:?}", self.context_id);
+            return Err(ErrorKind::MissingGl.into());
+        }
+        let descriptor = device.context_descriptor(context);
+        let attributes = device.context_descriptor_attributes(&descriptor);
+        let preserve = options.preserve_buffer.options(&attributes)?;
+
+        // Find the frame to drop to make room in the queue for the new front buffer
+        let dropped = if self.pending_surfaces.len() < self.options.queue_depth {
+            None
+        } else {
+            match self.options.present_mode {
+                PresentMode::Mailbox => Some(0),
+                PresentMode::FifoRelaxed => Some(self.pending_surfaces.len() - 1),
+                PresentMode::Fifo => {
+                    debug!("Presentation queue full for context {:?}", self.context_id);
+                    return Err(ErrorKind::QueueFull.into());
+                }
+            }
+        };
+
+        // Resolve the multisampled render target into the back buffer.
+        // If the swap chain has been resized since the producer drew to it,
+        // only the region the two sizes have in common is resolved.
+        if let (Some(gl), Some(multisampled)) = (options.gl, &self.multisampled) {
+            let size = self.size;
+            self.back_buffer
+                .with_framebuffer(device, context, |fbo| multisampled.resolve(gl, fbo, size))?;
+        }
+
+        // The new back buffer takes any requested resize
+        let front_epoch = self.epoch;
+        let resize = self
+            .pending_resize()
+            .filter(|_| self.options.resize_on_swap);
+        let size = resize.unwrap_or(self.size);
+
+        // Fetch a new back buffer, recycling presented buffers if possible.
+        // Until the buffers have been swapped, everything done here can be undone.
+        let recycled = self
+            .recycled_surfaces
+            .iter()
+            .position(|recycled| device.surface_info(&recycled.surface).size == size);
+        let mut dropped = dropped.and_then(|index| {
+            let (surface, frame) = self.pending_surfaces.remove(index)?.split();
+            Some((index, Some(surface), frame))
+        });
+        let (new_back_buffer, source) = match (recycled, &mut dropped) {
+            (Some(index), _) => {
+                debug!("Recyling surface for context {:?}", self.context_id);
+                let recycled = self.recycled_surfaces.remove(index);
+                let prewarmed = recycled.prewarmed;
+                let surface = self.wait_for_consumer(recycled, options.gl);
+                (surface, BackBufferSource::Recycled(index, prewarmed))
+            }
+            (None, Some((_, surface, _)))
+                if surface
+                    .as_ref()
+                    .is_some_and(|surface| device.surface_info(surface).size == size) =>
+            {
+                debug!("Recyling dropped surface for context {:?}", self.context_id);
+                (surface.take().unwrap(), BackBufferSource::Dropped)
+            }
+            (None, _) => {
+                debug!(
+                    "Creating a new surface ({:?}) for context {:?}",
+                    size, self.context_id
+                );
+                let surface_type = SurfaceType::Generic { size };
+                match device.create_surface(context, self.surface_access, surface_type) {
+                    Ok(surface) => (surface, BackBufferSource::Created),
+                    Err(err) => {
+                        self.restore_dropped_frame(dropped, None);
+                        return Err(err.into());
+                    }
+                }
+            }
+        };
+
+        let back_info = device.surface_info(&new_back_buffer);
+
+        // Swap the buffers, putting everything back if that fails
+        debug!(
+            "Surface {:?} is the new back buffer for context {:?}",
+            back_info.id, self.context_id
+        );
+        let new_front_buffer = match self.back_buffer.take_surface(device, context) {
+            Ok(surface) => surface,
+            Err(err) => {
+                self.restore_back_buffer_source(device, context, new_back_buffer, source, dropped);
+                return Err(err);
+            }
+        };
+        if let Err((err, new_back_buffer)) =
+            self.back_buffer
+                .try_replace_surface(device, context, new_back_buffer)
+        {
+            debug!("Restoring back buffer for context {:?}", self.context_id);
+            let restored = self
+                .back_buffer
+                .replace_surface(device, context, new_front_buffer);
+            self.restore_back_buffer_source(device, context, new_back_buffer, source, dropped);
+            restored?;
+            return Err(err);
+        }
+
+        // Drop the frame which made room in the queue
+        let mut damage = options.damage;
+        if let Some((_, surface, old_front_buffer)) = dropped {
+            debug!(
+                "Dropping frame {:?} ({:?}) for context {:?}",
+                old_front_buffer.id, old_front_buffer.size, self.context_id
+            );
+            self.stats.dropped += 1;
+            self.stale_fences.extend(old_front_buffer.fence);
+            // The damage from the dropped frame is carried over to the frame which follows it
+            match self.pending_surfaces.front_mut() {
+                Some(next) if self.options.present_mode == PresentMode::Mailbox => {
+                    next.damage = merge_damage(old_front_buffer.damage, next.damage.take());
+                }
+                _ => damage = merge_damage(old_front_buffer.damage, damage),
+            }
+            if let Some(surface) = surface {
+                self.recycle_surface(surface);
+            }
+        }
+
+        // Apply any requested resize
+        let resized = resize.is_some();
+        if let Some(size) = resize {
+            debug!("Resizing context {:?} to {:?}", self.context_id, size);
+            self.size = size;
+            self.epoch.0 += 1;
+            self.requested_size = None;
+        }
+
+        let mut preserve_error = None;
+        let front_info = device.surface_info(&new_front_buffer);
+        let frame_id = FrameId(self.stats.presented);
+        // A frame of a different size to its predecessor is damaged everywhere
+        if self.damage_history.back().map(|previous| previous.size) != Some(front_info.size) {
+            damage = None;
+        }
+        let region = damage.as_ref().map(|damage| {
+            damage
+                .iter()
+                .fold(Box2D::zero(), |region, rect| region.union(rect))
+        });
+        if let Some(PreserveOptions {
+            gl,
+            buffers,
+            filter,
+        }) = preserve
+        {
+            // Only the regions drawn since the back buffer was last presented need to be copied
+            let (src, dst) = match self.stale_region(&front_info, &back_info, region) {
+                Some(stale) => (stale, stale),
+                None => (
+                    Box2D::from_size(front_info.size),
+                    Box2D::from_size(back_info.size),
+                ),
+            };
+            if !src.is_empty() {
+                let _state = GlStateGuard::new(gl);
+                gl.bind_framebuffer(gl::READ_FRAMEBUFFER, front_info.framebuffer_object);
+                gl.bind_framebuffer(gl::DRAW_FRAMEBUFFER, back_info.framebuffer_object);
+                gl.blit_framebuffer(
+                    src.min.x, src.min.y, src.max.x, src.max.y, dst.min.x, dst.min.y, dst.max.x,
+                    dst.max.y, buffers, filter,
+                );
+                let error = gl.get_error();
+                if error != gl::NO_ERROR {
+                    debug!(
+                        "Failed to preserve the back buffer for context {:?} ({:#x})",
+                        self.context_id, error
+                    );
+                    preserve_error = Some(error);
+                }
+            }
+        }
+
+        // Invalidate the new back buffer if its contents are not needed
+        let invalidated = match options.gl {
+            Some(gl) if self.options.invalidate_back_buffer && preserve.is_none() => {
+                self.invalidate_back_buffer(device, context, gl, &back_info, &attributes)
+            }
+            _ => false,
+        };
+
+        // Fence the new front buffer
+        let fence = match options.gl {
+            Some(gl) if self.options.fence_sync => Some(Fence::insert(gl)),
+            _ => None,
+        };
+
+        // Update the state
+        debug!(
+            "Surface {:?} is the new front buffer for context {:?}",
+            front_info.id, self.context_id
+        );
+        self.stats.presented += 1;
+        if self.damage_history.len() >= MAX_DAMAGE_HISTORY {
+            self.damage_history.pop_front();
+        }
+        self.damage_history.push_back(FrameDamage {
+            id: frame_id,
+            size: front_info.size,
+            region,
+        });
+        self.pending_surfaces.push_back(PendingFrame {
+            id: frame_id,
+            surface: new_front_buffer,
+            metadata: options.metadata,
+            fence,
+            swapped_at: Instant::now(),
+            epoch: front_epoch,
+            size: front_info.size,
+            damage,
+        });
+        self.surface_frames.insert(front_info.id.0, frame_id);
+        // An invalidated back buffer has undefined contents, so is treated as new
+        self.back_buffer_age = if preserve.is_some() && preserve_error.is_none() {
+            1
+        } else if invalidated {
+            0
+        } else {
+            match self.surface_frames.get(&back_info.id.0) {
+                Some(&FrameId(drawn)) => self.stats.presented - drawn,
+                None => 0,
+            }
+        };
+        #[cfg(feature = "async")]
+        self.wake_consumers();
+
+        // The buffers have been swapped, so housekeeping failures are not reported as errors
+        if let Err(err) = self.trim_pool(device, context, options.gl) {
+            debug!(
+                "Failed to trim the pool for context {:?} ({:?})",
+                self.context_id, err
+            );
+        }
+        if let (Some(gl), Some(multisampled)) = (options.gl, &mut self.multisampled) {
+            if multisampled.size != self.size {
+                multisampled.allocate(gl, self.size);
+            }
+        }
+        if resized {
+            if let Err(err) = self.prewarm(device, context) {
+                debug!(
+                    "Failed to pre-warm surfaces for context {:?} ({:?})",
+                    self.context_id, err
+                );
+            }
+        }
+        if let Some(gl) = options.gl {
+            self.collect_gl_garbage(gl);
+        }
+
+        if let Some(error) = preserve_error {
+            return Err(ErrorKind::PreserveFailed(frame_id, error).into());
+        }
+        Ok(frame_id)
+    }
+
+    // Put back the surface fetched to be the new back buffer after swapping buffers failed,
+    // together with the frame dropped to make room in the queue.
+    // Called by the producer.
+    fn restore_back_buffer_source(
+        &mut self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        mut surface: Device::Surface,
+        source: BackBufferSource,
+        dropped: Option<DroppedFrame<Device::Surface, Metadata>>,
+    ) {
+        match source {
+            BackBufferSource::Recycled(index, prewarmed) => {
+                self.recycled_surfaces.insert(
+                    index,
+                    RecycledSurface {
+                        surface,
+                        fence: None,
+                        prewarmed,
+                    },
+                );
+                self.restore_dropped_frame(dropped, None);
+            }
+            BackBufferSource::Dropped => self.restore_dropped_frame(dropped, Some(surface)),
+            BackBufferSource::Created => {
+                let _ = device.destroy_surface(context, &mut surface);
+                self.restore_dropped_frame(dropped, None);
+            }
+        }
+    }
+
+    // Put a frame dropped to make room in the queue back where it was.
+    // The frame's surface is given if it was taken to be the new back buffer.
+    fn restore_dropped_frame(
+        &mut self,
+        dropped: Option<DroppedFrame<Device::Surface, Metadata>>,
+        surface: Option<Device::Surface>,
+    ) {
+        if let Some((index, dropped_surface, frame)) = dropped {
+            if let Some(surface) = dropped_surface.or(surface) {
+                self.pending_surfaces.insert(index, frame.join(surface));
+            }
+        }
+    }
+
+    // Get the multisampled render target, creating it or resizing it if necessary.
+    // Returns `None` if the swap chain is not multisampled.
+    // Called by the producer.
+    fn multisampled_framebuffer(
+        &mut self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        gl: &Gl,
+    ) -> Result<Option<GLuint>, Error> {
+        self.validate_context(device, context)?;
+        self.collect_gl_garbage(gl);
+        if self.options.samples == 0 {
+            return Ok(None);
+        }
+        match self.multisampled {
+            Some(ref mut multisampled) if multisampled.size != self.size => {
+                multisampled.allocate(gl, self.size)
+            }
+            Some(_) => (),
+            None => {
+                debug!(
+                    "Creating a multisampled framebuffer ({:?}) for context {:?}",
+                    self.size, self.context_id
+                );
+                let descriptor = device.context_descriptor(context);
+                let attributes = device.context_descriptor_attributes(&descriptor);
+                let multisampled =
+                    MultisampledFramebuffer::new(gl, self.size, self.options.samples, &attributes)?;
+                self.multisampled = Some(multisampled);
+            }
+        }
+        Ok(self
+            .multisampled
+            .as_ref()
+            .map(|multisampled| multisampled.framebuffer))
+    }
+
+    // Delete the multisampled render target.
+    // Called by the producer.
+    fn destroy_multisampled_framebuffer(
+        &mut self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        gl: &Gl,
+    ) -> Result<(), Error> {
+        self.validate_context(device, context)?;
+        self.collect_gl_garbage(gl);
+        if let Some(multisampled) = self.multisampled.take() {
+            multisampled.delete(gl);
+        }
+        Ok(())
+    }
+
+    // Delete the fences nobody is going to wait for,
+    // and the multisampled render targets left by destroyed swap chains for the producer context.
+    // Called by the producer.
+    fn collect_gl_garbage(&mut self, gl: &Gl) {
+        for fence in self.stale_fences.drain(..) {
+            fence.delete(gl);
+        }
+        let context_id = self.context_id;
+        let stale: Vec<_> = {
+            let mut garbage = self
+                .gl_garbage
+                .lock()
+                .unwrap_or_else(|err| err.into_inner());
+            let (stale, kept) = mem::take(&mut *garbage)
+                .into_iter()
+                .partition(|(id, _)| *id == context_id);
+            *garbage = kept;
+            stale
         };
-        *self = new_back_buffer;
-        Ok(())
+        for (_, multisampled) in stale {
+            debug!(
+                "Deleting multisampled framebuffer left for context {:?}",
+                context_id
+            );
+            multisampled.delete(gl);
+        }
     }
-    fn replace_surface_texture(
+
+    // Invalidate the contents of the back buffer, if the producer context supports it.
+    // Returns whether the back buffer was invalidated.
+    // Called by the producer.
+    fn invalidate_back_buffer(
         &mut self,
         device: &Device,
         context: &mut Device::Context,
-        surface_texture: Device::SurfaceTexture,
-    ) -> Result<(), Error> {
-        let surface = device
-            .destroy_surface_texture(context, surface_texture)
-            .map_err(|(err, _)| err)?;
-        self.replace_surface(device, context, surface)
+        gl: &Gl,
+        back_info: &SurfaceInfo,
+        attributes: &ContextAttributes,
+    ) -> bool {
+        if !*self
+            .invalidation_supported
+            .get_or_insert_with(|| supports_invalidation(gl))
+        {
+            return false;
+        }
+        debug!(
+            "Invalidating surface {:?} for context {:?}",
+            back_info.id, self.context_id
+        );
+        let result = self.back_buffer.with_framebuffer(device, context, |fbo| {
+            // The default framebuffer has different names for its attachments
+            let (color, depth, stencil) = if fbo == 0 {
+                (gl::COLOR, gl::DEPTH, gl::STENCIL)
+            } else {
+                (
+                    gl::COLOR_ATTACHMENT0,
+                    gl::DEPTH_ATTACHMENT,
+                    gl::STENCIL_ATTACHMENT,
+                )
+            };
+            let mut attachments = vec![color];
+            if attributes.flags.contains(ContextAttributeFlags::DEPTH) {
+                attachments.push(depth);
+            }
+            if attributes.flags.contains(ContextAttributeFlags::STENCIL) {
+                attachments.push(stencil);
+            }
+            let _state = GlStateGuard::new(gl);
+            gl.bind_framebuffer(gl::DRAW_FRAMEBUFFER, fbo);
+            gl.invalidate_framebuffer(gl::DRAW_FRAMEBUFFER, &attachments);
+            Ok(())
+        });
+        if let Err(err) = result {
+            debug!(
+                "Failed to invalidate surface {:?} for context {:?} ({:?})",
+                back_info.id, self.context_id, err
+            );
+            return false;
+        }
+        true
     }
-}
 
-impl<Device: DeviceAPI> SwapChainData<Device> {
-    // Returns `Ok` if `context` is the producer context for this swap chain.
-    fn validate_context(&self, device: &Device, context: &Device::Context) -> Result<(), Error> {
-        if self.context_id == device.context_id(context) {
-            Ok(())
-        } else {
-            Err(Error::IncompatibleContext)
+    // Get the region of the back buffer which is out of date with respect to the front buffer,
+    // given the region of the front buffer drawn in the current frame.
+    // Returns `None` if the whole back buffer may be out of date.
+    fn stale_region(
+        &self,
+        front_info: &SurfaceInfo,
+        back_info: &SurfaceInfo,
+        region: Option<Box2D<i32>>,
+    ) -> Option<Box2D<i32>> {
+        if front_info.size != back_info.size {
+            return None;
+        }
+        let drawn = *self.surface_frames.get(&back_info.id.0)?;
+        // The damage history must cover every frame drawn since the back buffer was presented
+        let first = FrameId(drawn.0 + 1);
+        let forgotten = match self.damage_history.front() {
+            Some(oldest) => oldest.id > first,
+            None => true,
+        };
+        if first.0 < self.stats.presented && forgotten {
+            return None;
         }
+        let mut stale = region?;
+        for damage in self
+            .damage_history
+            .iter()
+            .filter(|damage| damage.id > drawn)
+        {
+            stale = stale.union(&damage.region?);
+        }
+        Some(
+            stale
+                .intersection(&Box2D::from_size(back_info.size))
+                .unwrap_or_default(),
+        )
     }
 
-    // Swap the back and front buffers.
+    // Destroy recycled surfaces according to the eviction policy,
+    // until the pool is within its capacity.
     // Called by the producer.
-    // Returns an error if `context` is not the producer context for this swap chain.
-    fn swap_buffers(
+    fn trim_pool(
         &mut self,
         device: &mut Device,
         context: &mut Device::Context,
-        preserve_buffer: PreserveBuffer<'_>,
+        gl: Option<&Gl>,
     ) -> Result<(), Error> {
-        debug!("Swap buffers on context {:?}", self.context_id);
-        self.validate_context(device, context)?;
-
-        // Recycle the old front buffer
-        if let Some(old_front_buffer) = self.pending_surface.take() {
-            let SurfaceInfo { id, size, .. } = device.surface_info(&old_front_buffer);
-            debug!(
-                "Recycling surface {:?} ({:?}) for context {:?}",
-                id, size, self.context_id
-            );
-            self.recycle_surface(old_front_buffer);
+        let size = self.size;
+        let recycled_surfaces = mem::take(&mut self.recycled_surfaces);
+        let (mut kept, mut evicted): (Vec<_>, Vec<_>) = match self.options.eviction_policy {
+            EvictionPolicy::OldestFirst => (recycled_surfaces, Vec::new()),
+            EvictionPolicy::MatchingSize => recycled_surfaces
+                .into_iter()
+                .partition(|recycled| device.surface_info(&recycled.surface).size == size),
+        };
+        let capacity = self
+            .options
+            .pool_capacity
+            .max(self.options.prewarmed_surfaces);
+        let excess = kept.len().saturating_sub(capacity);
+        evicted.extend(kept.drain(..excess));
+        self.recycled_surfaces = kept;
+        for recycled in evicted {
+            debug!("Destroying a surface for context {:?}", self.context_id);
+            let surface = self.wait_for_consumer(recycled, gl);
+            self.surface_frames
+                .remove(&device.surface_info(&surface).id.0);
+            self.destroy_or_orphan(device, context, surface)?;
         }
+        Ok(())
+    }
 
-        // Fetch a new back buffer, recycling presented buffers if possible.
-        let new_back_buffer = self
+    // Allocate surfaces of the current size until the pool holds the number of pre-warmed surfaces.
+    // Called by the producer.
+    fn prewarm(&mut self, device: &mut Device, context: &mut Device::Context) -> Result<(), Error> {
+        let size = self.size;
+        let matching = self
             .recycled_surfaces
             .iter()
-            .position(|surface| device.surface_info(surface).size == self.size)
-            .map(|index| {
-                debug!("Recyling surface for context {:?}", self.context_id);
-                Ok(self.recycled_surfaces.swap_remove(index))
-            })
-            .unwrap_or_else(|| {
-                debug!(
-                    "Creating a new surface ({:?}) for context {:?}",
-                    self.size, self.context_id
-                );
-                let surface_type = SurfaceType::Generic { size: self.size };
-                device.create_surface(context, self.surface_access, surface_type)
-            })?;
-
-        let back_info = device.surface_info(&new_back_buffer);
-
-        // Swap the buffers
-        debug!(
-            "Surface {:?} is the new back buffer for context {:?}",
-            device.surface_info(&new_back_buffer).id,
-            self.context_id
-        );
-        let new_front_buffer = self.back_buffer.take_surface(device, context)?;
-        self.back_buffer
-            .replace_surface(device, context, new_back_buffer)?;
-
-        if let PreserveBuffer::Yes(gl) = preserve_buffer {
-            let front_info = device.surface_info(&new_front_buffer);
-            gl.bind_framebuffer(gl::READ_FRAMEBUFFER, front_info.framebuffer_object);
-            debug_assert_eq!(gl.get_error(), gl::NO_ERROR);
-            gl.bind_framebuffer(gl::DRAW_FRAMEBUFFER, back_info.framebuffer_object);
-            debug_assert_eq!(gl.get_error(), gl::NO_ERROR);
-            gl.blit_framebuffer(
-                0,
-                0,
-                front_info.size.width,
-                front_info.size.height,
-                0,
+            .filter(|recycled| device.surface_info(&recycled.surface).size == size)
+            .count();
+        for _ in matching..self.options.prewarmed_surfaces {
+            debug!(
+                "Pre-warming a surface ({:?}) for context {:?}",
+                size, self.context_id
+            );
+            let surface_type = SurfaceType::Generic { size };
+            let surface = device.create_surface(context, self.surface_access, surface_type)?;
+            // Pre-warmed surfaces go at the oldest end of the pool,
+            // so they are reused first and never mistaken for the most recent frame.
+            self.recycled_surfaces.insert(
                 0,
-                back_info.size.width,
-                back_info.size.height,
-                gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT,
-                gl::NEAREST,
+                RecycledSurface {
+                    surface,
+                    fence: None,
+                    prewarmed: true,
+                },
             );
-            debug_assert_eq!(gl.get_error(), gl::NO_ERROR);
         }
+        Ok(())
+    }
 
-        // Update the state
-        debug!(
-            "Surface {:?} is the new front buffer for context {:?}",
-            device.surface_info(&new_front_buffer).id,
-            self.context_id
-        );
-        self.pending_surface = Some(new_front_buffer);
-        for mut surface in self.recycled_surfaces.drain(..) {
-            debug!("Destroying a surface for context {:?}", self.context_id);
-            device.destroy_surface(context, &mut surface)?;
+    // Destroy all the recycled surfaces.
+    // Called by the producer.
+    fn destroy_pool(
+        &mut self,
+        device: &mut Device,
+        context: &mut Device::Context,
+    ) -> Result<(), Error> {
+        for recycled in mem::take(&mut self.recycled_surfaces) {
+            self.destroy_or_orphan(device, context, recycled.surface)?;
         }
+        Ok(())
+    }
 
+    // Destroy a surface, keeping it to be destroyed by `collect_garbage` if that fails.
+    // Called by the producer.
+    fn destroy_or_orphan(
+        &mut self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        mut surface: Device::Surface,
+    ) -> Result<(), Error> {
+        if let Err(err) = device.destroy_surface(context, &mut surface) {
+            self.orphans.push(surface);
+            return Err(err.into());
+        }
         Ok(())
     }
 
@@ -251,32 +1944,84 @@ impl<Device: DeviceAPI> SwapChainData<Device> {
         &mut self,
         device: &mut Device,
         context: &mut Device::Context,
-        other: &mut SwapChainData<Device>,
+        other: &mut SwapChainData<Device, Metadata>,
     ) -> Result<(), Error> {
         self.validate_context(device, context)?;
         other.validate_context(device, context)?;
+        if self.is_attached() || !other.is_attached() {
+            return Err(ErrorKind::WrongAttachment.into());
+        }
         let our_surface = self.back_buffer.take_surface(device, context)?;
-        let their_surface = other.back_buffer.take_surface(device, context)?;
+        let their_surface = match other.back_buffer.take_surface(device, context) {
+            Ok(surface) => surface,
+            Err(err) => {
+                self.back_buffer
+                    .replace_surface(device, context, our_surface)?;
+                return Err(err);
+            }
+        };
         mem::swap(&mut self.back_buffer, &mut other.back_buffer);
-        self.back_buffer
-            .replace_surface(device, context, our_surface)?;
+        let (err, our_surface, their_surface) =
+            match self
+                .back_buffer
+                .try_replace_surface(device, context, our_surface)
+            {
+                Err((err, our_surface)) => (err, our_surface, their_surface),
+                Ok(()) => {
+                    match other
+                        .back_buffer
+                        .try_replace_surface(device, context, their_surface)
+                    {
+                        Ok(()) => return Ok(()),
+                        Err((err, their_surface)) => {
+                            match self.back_buffer.take_surface(device, context) {
+                                Ok(our_surface) => (err, our_surface, their_surface),
+                                Err(err) => {
+                                    // Surfaces must be destroyed rather than dropped, so give it back
+                                    let _ = other.back_buffer.replace_surface(
+                                        device,
+                                        context,
+                                        their_surface,
+                                    );
+                                    return Err(err);
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+
+        // Put the back buffers back the way they were
+        debug!("Restoring back buffers for context {:?}", self.context_id);
+        mem::swap(&mut self.back_buffer, &mut other.back_buffer);
+        let restored = self
+            .back_buffer
+            .replace_surface(device, context, our_surface);
         other
             .back_buffer
             .replace_surface(device, context, their_surface)?;
-        Ok(())
+        restored?;
+        Err(err)
     }
 
     // Resize the swap chain.
     // This creates a new back buffer of the appropriate size,
     // and destroys the old one.
+    // If `preserve` is given, the old back buffer is blitted to the new one first.
+    // If the swap chain has pre-warmed surfaces, new ones of the appropriate size are allocated.
     // Called by the producer.
     // Returns an error if `context` is not the producer context for this swap chain.
     // Returns an error if `size` is smaller than (1, 1).
+    // Returns an error if the filter to preserve the contents is invalid, or copying them fails.
+    // Returns an error if the contents of a multisampled swap chain are preserved on OpenGL ES.
+    // Returns an error if copying the contents to the multisampled render target fails,
+    // in which case the swap chain is still resized.
     fn resize(
         &mut self,
         device: &mut Device,
         context: &mut Device::Context,
         size: Size2D<i32>,
+        preserve: Option<(&Gl, ResizeAnchor, GLenum)>,
     ) -> Result<(), Error> {
         debug!(
             "Resizing context {:?} to {:?}",
@@ -285,16 +2030,88 @@ impl<Device: DeviceAPI> SwapChainData<Device> {
         );
         self.validate_context(device, context)?;
         if (size.width < 1) || (size.height < 1) {
-            return Err(Error::Failed);
+            return Err(ErrorKind::InvalidSize(size).into());
         }
+        let preserve = match preserve {
+            Some((gl, anchor, filter)) => {
+                self.collect_gl_garbage(gl);
+                let descriptor = device.context_descriptor(context);
+                let attributes = device.context_descriptor_attributes(&descriptor);
+                if self.multisampled.is_some() && (gl.get_type() == GlType::Gles) {
+                    debug!("OpenGL ES cannot preserve the contents of multisampled framebuffers");
+                    return Err(ErrorKind::InvalidPreserveOptions.into());
+                }
+                Some((resize_preserve_options(gl, filter, &attributes)?, anchor))
+            }
+            None => None,
+        };
         let surface_type = SurfaceType::Generic { size };
-        let new_back_buffer = device.create_surface(context, self.surface_access, surface_type)?;
-        let mut old_back_buffer = self.back_buffer.take_surface(device, context)?;
-        self.back_buffer
-            .replace_surface(device, context, new_back_buffer)?;
-        device.destroy_surface(context, &mut old_back_buffer)?;
+        let mut new_back_buffer =
+            device.create_surface(context, self.surface_access, surface_type)?;
+        let new_info = device.surface_info(&new_back_buffer);
+
+        // Copy the old contents to the new back buffer
+        if let Some((options, anchor)) = preserve {
+            let old_size = self.size;
+            let result = self
+                .back_buffer
+                .with_framebuffer(device, context, |old_fbo| {
+                    blit_resized(&options, anchor, old_fbo, old_size, &new_info)
+                });
+            if let Err(err) = result {
+                let _ = device.destroy_surface(context, &mut new_back_buffer);
+                return Err(err);
+            }
+        }
+
+        // Replace the back buffer, putting the old one back if that fails
+        let old_back_buffer = match self.back_buffer.take_surface(device, context) {
+            Ok(surface) => surface,
+            Err(err) => {
+                let _ = device.destroy_surface(context, &mut new_back_buffer);
+                return Err(err);
+            }
+        };
+        if let Err((err, mut new_back_buffer)) =
+            self.back_buffer
+                .try_replace_surface(device, context, new_back_buffer)
+        {
+            debug!("Restoring back buffer for context {:?}", self.context_id);
+            let _ = device.destroy_surface(context, &mut new_back_buffer);
+            self.back_buffer
+                .replace_surface(device, context, old_back_buffer)?;
+            return Err(err);
+        }
+
+        self.surface_frames
+            .remove(&device.surface_info(&old_back_buffer).id.0);
         self.size = size;
-        Ok(())
+        self.epoch.0 += 1;
+        self.back_buffer_age = 0;
+
+        // The multisampled render target is what the producer draws to, so it gets the preserved contents too
+        let mut result = Ok(());
+        if let (Some((options, _)), Some(multisampled)) = (preserve, &mut self.multisampled) {
+            multisampled.allocate(options.gl, size);
+            result = self.back_buffer.with_framebuffer(device, context, |fbo| {
+                multisampled.fill(options.gl, fbo, options.buffers)
+            });
+        }
+
+        // The back buffer has been replaced, so housekeeping failures are not reported as errors
+        if let Err(err) = self.destroy_or_orphan(device, context, old_back_buffer) {
+            debug!(
+                "Failed to destroy the old back buffer for context {:?} ({:?})",
+                self.context_id, err
+            );
+        }
+        if let Err(err) = self.prewarm(device, context) {
+            debug!(
+                "Failed to pre-warm surfaces for context {:?} ({:?})",
+                self.context_id, err
+            );
+        }
+        result
     }
 
     // Get the current size.
@@ -303,6 +2120,42 @@ impl<Device: DeviceAPI> SwapChainData<Device> {
         self.size
     }
 
+    // Ask the producer to resize the swap chain.
+    // Called by a consumer.
+    fn request_resize(&mut self, size: Size2D<i32>) {
+        if (size.width < 1) || (size.height < 1) {
+            debug!(
+                "Ignoring resize to {:?} for context {:?}",
+                size, self.context_id
+            );
+            return;
+        }
+        self.requested_size = Some(size);
+    }
+
+    // Get the most recent size requested by a consumer, if it differs from the current size.
+    // Called by the producer.
+    fn pending_resize(&self) -> Option<Size2D<i32>> {
+        self.requested_size.filter(|size| *size != self.size)
+    }
+
+    // Get the number of frames since the contents of the back buffer were presented.
+    // Called by the producer.
+    fn back_buffer_age(&self) -> u64 {
+        self.back_buffer_age
+    }
+
+    // Get the number of times the swap chain has been resized.
+    // Called by a consumer.
+    fn epoch(&self) -> Epoch {
+        self.epoch
+    }
+
+    // Get the presentation policy.
+    fn present_mode(&self) -> PresentMode {
+        self.options.present_mode
+    }
+
     // Take the current back buffer.
     // Called by a producer.
     fn take_surface_texture(
@@ -327,26 +2180,179 @@ impl<Device: DeviceAPI> SwapChainData<Device> {
             .replace_surface_texture(device, context, surface_texture)
     }
 
-    // Take the current front buffer.
+    // Take the oldest front buffer.
     // Returns the most recent recycled surface if there is no current front buffer.
     // Called by a consumer.
     fn take_surface(&mut self) -> Option<Device::Surface> {
-        self.pending_surface
-            .take()
-            .or_else(|| self.recycled_surfaces.pop())
+        self.take_pending_surface().or_else(|| {
+            if self.recycled_surfaces.last()?.prewarmed {
+                return None;
+            }
+            let recycled = self.recycled_surfaces.pop()?;
+            self.discard_fence(recycled.fence);
+            Some(recycled.surface)
+        })
     }
 
-    // Take the current front buffer.
+    // Take the oldest front buffer.
     // Returns `None` if there is no current front buffer.
     // Called by a consumer.
     fn take_pending_surface(&mut self) -> Option<Device::Surface> {
-        self.pending_surface.take()
+        self.take_frame().map(|frame| frame.surface)
+    }
+
+    // Take the oldest front buffer, together with its frame id and metadata.
+    // Returns `None` if there is no current front buffer.
+    // Called by a consumer.
+    fn take_frame(&mut self) -> Option<Frame<Device::Surface, Metadata>> {
+        let (frame, fence) = self.take_frame_with_fence()?;
+        self.discard_fence(fence);
+        Some(frame)
+    }
+
+    // Leave a fence a consumer is not going to wait for to be deleted by the producer.
+    // Called by a consumer.
+    fn discard_fence(&mut self, fence: Option<Fence>) {
+        if let Some(fence) = fence {
+            debug!(
+                "Discarding fence for context {:?}, use take_frame_with_fence to wait for it",
+                self.context_id
+            );
+            self.stale_fences.push(fence);
+        }
+    }
+
+    // Take the oldest front buffer, together with the fence signalled when it was finished.
+    // Returns `None` if there is no current front buffer.
+    // Called by a consumer.
+    fn take_frame_with_fence(&mut self) -> Option<FencedFrame<Device::Surface, Metadata>> {
+        let mut frame = self.pending_surfaces.pop_front()?;
+        debug!(
+            "Taking frame {:?} for context {:?}",
+            frame.id, self.context_id
+        );
+        self.stats.taken += 1;
+        if self.in_flight.len() >= MAX_IN_FLIGHT_FRAMES {
+            self.in_flight.pop_front();
+        }
+        self.in_flight.push_back((frame.id, frame.swapped_at));
+        let fence = frame.fence.take();
+        Some((frame.into_frame(), fence))
+    }
+
+    // Get the number of frames presented, taken and dropped so far.
+    fn frame_stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    // Record that the frame `frame_id` was displayed at `timestamp`.
+    // Frames taken before it are assumed to have been displayed or skipped.
+    // Called by a consumer.
+    fn present_feedback(&mut self, frame_id: FrameId, timestamp: Instant) {
+        while let Some((id, swapped_at)) = self.in_flight.pop_front() {
+            if id == frame_id {
+                debug!(
+                    "Frame {:?} displayed for context {:?}",
+                    frame_id, self.context_id
+                );
+                self.feedback = Some(PresentationFeedback {
+                    frame_id,
+                    swapped_at,
+                    displayed_at: timestamp,
+                });
+                return;
+            } else if id > frame_id {
+                self.in_flight.push_front((id, swapped_at));
+                break;
+            }
+        }
+        debug!(
+            "Ignoring feedback for frame {:?} for context {:?}",
+            frame_id, self.context_id
+        );
+    }
+
+    // Get the feedback for the most recently displayed frame.
+    // Called by the producer.
+    fn presentation_feedback(&self) -> Option<PresentationFeedback> {
+        self.feedback
+    }
+
+    // Is there a front buffer ready to be taken?
+    // Called by a consumer.
+    fn has_pending_surface(&self) -> bool {
+        !self.pending_surfaces.is_empty()
+    }
+
+    // Does the producer have to wait for the consumer before swapping buffers?
+    // Called by the producer.
+    fn is_queue_full(&self) -> bool {
+        (self.options.present_mode == PresentMode::Fifo)
+            && (self.pending_surfaces.len() >= self.options.queue_depth)
+    }
+
+    // Wake `waker` the next time the producer swaps buffers.
+    // Called by a consumer.
+    #[cfg(feature = "async")]
+    fn register_waker(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|other| other.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    // Wake the consumer tasks waiting for a front buffer.
+    // Called by the producer.
+    #[cfg(feature = "async")]
+    fn wake_consumers(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
     }
 
     // Recycle the current front buffer.
+    // If the swap chain has been destroyed, the surface is left for the producer to destroy.
     // Called by a consumer.
     fn recycle_surface(&mut self, surface: Device::Surface) {
-        self.recycled_surfaces.push(surface)
+        if self.destroyed {
+            debug!("Orphaning surface for context {:?}", self.context_id);
+            return self.orphans.push(surface);
+        }
+        self.recycled_surfaces.push(RecycledSurface {
+            surface,
+            fence: None,
+            prewarmed: false,
+        })
+    }
+
+    // Recycle the current front buffer, once `fence` is signalled.
+    // If the swap chain has been destroyed, the surface is left for the producer to destroy.
+    // Called by a consumer.
+    fn recycle_surface_with_fence(&mut self, surface: Device::Surface, fence: Fence) {
+        if self.destroyed {
+            debug!("Orphaning surface for context {:?}", self.context_id);
+            self.stale_fences.push(fence);
+            return self.orphans.push(surface);
+        }
+        self.recycled_surfaces.push(RecycledSurface {
+            surface,
+            fence: Some(fence),
+            prewarmed: false,
+        })
+    }
+
+    // Make the producer wait until the consumer has finished with a recycled surface.
+    // If there are no GL bindings, the fence is left for a later swap to delete.
+    // Called by the producer.
+    fn wait_for_consumer(
+        &mut self,
+        recycled: RecycledSurface<Device>,
+        gl: Option<&Gl>,
+    ) -> Device::Surface {
+        match (recycled.fence, gl) {
+            (Some(fence), Some(gl)) => fence.wait(gl),
+            (fence, _) => self.stale_fences.extend(fence),
+        }
+        recycled.surface
     }
 
     // Clear the current back buffer.
@@ -357,102 +2363,33 @@ impl<Device: DeviceAPI> SwapChainData<Device> {
         device: &mut Device,
         context: &mut Device::Context,
         gl: &Gl,
-        color: [f32; 4],
+        options: &ClearOptions,
     ) -> Result<(), Error> {
         self.validate_context(device, context)?;
-
-        // Save the current GL state
-        let mut bound_fbos = [0, 0];
-        let mut clear_color = [0., 0., 0., 0.];
-        let mut clear_depth = [0.];
-        let mut clear_stencil = [0];
-        let mut color_mask = [0, 0, 0, 0];
-        let mut depth_mask = [0];
-        let mut stencil_mask = [0];
-        let scissor_enabled = gl.is_enabled(gl::SCISSOR_TEST);
-        let rasterizer_enabled = gl.is_enabled(gl::RASTERIZER_DISCARD);
-        unsafe {
-            gl.get_integer_v(gl::DRAW_FRAMEBUFFER_BINDING, &mut bound_fbos[0..]);
-            gl.get_integer_v(gl::READ_FRAMEBUFFER_BINDING, &mut bound_fbos[1..]);
-            gl.get_float_v(gl::COLOR_CLEAR_VALUE, &mut clear_color[..]);
-            gl.get_float_v(gl::DEPTH_CLEAR_VALUE, &mut clear_depth[..]);
-            gl.get_integer_v(gl::STENCIL_CLEAR_VALUE, &mut clear_stencil[..]);
-            gl.get_boolean_v(gl::DEPTH_WRITEMASK, &mut depth_mask[..]);
-            gl.get_integer_v(gl::STENCIL_WRITEMASK, &mut stencil_mask[..]);
-            gl.get_boolean_v(gl::COLOR_WRITEMASK, &mut color_mask[..]);
-        }
-
-        // Make the back buffer the current surface
-        let reattach = if self.is_attached() {
-            None
-        } else {
-            let surface = self.back_buffer.take_surface(device, context)?;
-            let mut reattach = device.unbind_surface_from_context(context)?;
-            if let Err((err, mut surface)) = device.bind_surface_to_context(context, surface) {
-                debug!("Oh no, destroying surfaces");
-                let _ = device.destroy_surface(context, &mut surface);
-                if let Some(ref mut reattach) = reattach {
-                    let _ = device.destroy_surface(context, reattach);
-                }
-                return Err(err);
-            }
-            reattach
-        };
-
-        // Clear it
-        let fbo = device
-            .context_surface_info(context)
-            .unwrap()
-            .unwrap()
-            .framebuffer_object;
-        gl.bind_framebuffer(gl::FRAMEBUFFER, fbo);
-        gl.clear_color(color[0], color[1], color[2], color[3]);
-        gl.clear_depth(1.);
-        gl.clear_stencil(0);
-        gl.disable(gl::SCISSOR_TEST);
-        gl.disable(gl::RASTERIZER_DISCARD);
-        gl.depth_mask(true);
-        gl.stencil_mask(0xFFFFFFFF);
-        gl.color_mask(true, true, true, true);
-        gl.clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT);
-
-        // Reattach the old surface
-        if let Some(surface) = reattach {
-            let mut old_surface = device.unbind_surface_from_context(context)?.unwrap();
-            if let Err((err, mut surface)) = device.bind_surface_to_context(context, surface) {
-                debug!("Oh no, destroying surface");
-                let _ = device.destroy_surface(context, &mut surface);
-                let _ = device.destroy_surface(context, &mut old_surface);
-                return Err(err);
+        self.collect_gl_garbage(gl);
+        if let Some(scissor) = options.scissor {
+            if scissor.is_empty() {
+                return Ok(());
             }
-            self.back_buffer
-                .replace_surface(device, context, old_surface)?;
         }
 
-        // Restore the GL state
-        gl.bind_framebuffer(gl::DRAW_FRAMEBUFFER, bound_fbos[0] as GLuint);
-        gl.bind_framebuffer(gl::READ_FRAMEBUFFER, bound_fbos[1] as GLuint);
-        gl.clear_color(
-            clear_color[0],
-            clear_color[1],
-            clear_color[2],
-            clear_color[3],
-        );
-        gl.color_mask(
-            color_mask[0] != 0,
-            color_mask[1] != 0,
-            color_mask[2] != 0,
-            color_mask[3] != 0,
-        );
-        gl.clear_depth(clear_depth[0] as f64);
-        gl.clear_stencil(clear_stencil[0]);
-        gl.depth_mask(depth_mask[0] != 0);
-        gl.stencil_mask(stencil_mask[0] as gl::GLuint);
-        if scissor_enabled {
-            gl.enable(gl::SCISSOR_TEST);
-        }
-        if rasterizer_enabled {
-            gl.enable(gl::RASTERIZER_DISCARD);
+        // Save the current GL state, which is restored when the guard is dropped
+        let _state = GlStateGuard::new(gl);
+
+        // Clear the back buffer through its framebuffer object,
+        // which only changes the context's surface if the backend requires it
+        let buffers = self.back_buffer.with_framebuffer(device, context, |fbo| {
+            gl.bind_framebuffer(gl::FRAMEBUFFER, fbo);
+            let buffers = options.apply(gl);
+            gl.clear(buffers);
+            Ok(buffers)
+        })?;
+        if let Some(ref mut multisampled) = self.multisampled {
+            if multisampled.size != self.size {
+                multisampled.allocate(gl, self.size);
+            }
+            gl.bind_framebuffer(gl::FRAMEBUFFER, multisampled.framebuffer);
+            gl.clear(buffers);
         }
 
         Ok(())
@@ -466,78 +2403,405 @@ impl<Device: DeviceAPI> SwapChainData<Device> {
         }
     }
 
-    // Destroy the swap chain.
+    // Give the swap chain a new back buffer if it is missing one.
+    // Called by the producer.
+    // Returns an error if `context` is not the producer context for this swap chain.
+    // Returns an error if the new back buffer cannot be created.
+    fn repair(
+        &mut self,
+        device: &mut Device,
+        context: &mut Device::Context,
+    ) -> Result<RepairReport, Error> {
+        self.validate_context(device, context)?;
+        let attached = self.is_attached();
+        let missing = match self.back_buffer {
+            BackBuffer::Attached => device.context_surface_info(context)?.is_none(),
+            BackBuffer::Detached(_) => false,
+            BackBuffer::TakenAttached | BackBuffer::TakenDetached => true,
+        };
+        if missing {
+            debug!(
+                "Replacing the missing back buffer ({:?}) for context {:?}",
+                self.size, self.context_id
+            );
+            let surface_type = SurfaceType::Generic { size: self.size };
+            let surface = device.create_surface(context, self.surface_access, surface_type)?;
+            self.back_buffer = if attached {
+                BackBuffer::TakenAttached
+            } else {
+                BackBuffer::TakenDetached
+            };
+            self.back_buffer.replace_surface(device, context, surface)?;
+            self.back_buffer_age = 0;
+        }
+        Ok(RepairReport {
+            back_buffer_replaced: missing,
+            attached,
+        })
+    }
+
+    // Destroy the swap chain.
+    // Any surfaces which cannot be destroyed are kept, along with surfaces recycled later,
+    // until the garbage is collected.
+    // Destroying a swap chain which has already been destroyed collects its garbage.
+    // The multisampled render target is deleted if `gl` is given,
+    // and is otherwise left to be deleted by the next producer call with GL bindings
+    // on a swap chain which shares its GL garbage.
+    // Called by the producer.
+    // Returns an error if `context` is not the producer context for this swap chain.
+    fn destroy(
+        &mut self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        gl: Option<&Gl>,
+    ) -> Result<(), Error> {
+        if !self.destroyed {
+            self.validate_context(device, context)?;
+            match (self.multisampled.take(), gl) {
+                (Some(multisampled), Some(gl)) => multisampled.delete(gl),
+                (Some(multisampled), None) => {
+                    debug!(
+                        "Leaving multisampled framebuffer to be deleted for context {:?}",
+                        self.context_id
+                    );
+                    self.gl_garbage
+                        .lock()
+                        .unwrap_or_else(|err| err.into_inner())
+                        .push((self.context_id, multisampled));
+                }
+                (None, _) => (),
+            }
+            let surfaces = self
+                .pending_surfaces
+                .drain(..)
+                .map(|frame| frame.surface)
+                .chain(self.back_buffer.take_surface(device, context))
+                .chain(
+                    self.recycled_surfaces
+                        .drain(..)
+                        .map(|recycled| recycled.surface),
+                );
+            self.orphans.extend(surfaces);
+            self.surface_frames.clear();
+            self.destroyed = true;
+            #[cfg(feature = "async")]
+            self.wake_consumers();
+        }
+        self.collect_garbage(device, context)?;
+        if let Some(gl) = gl {
+            self.collect_gl_garbage(gl);
+        }
+        Ok(())
+    }
+
+    // Record the producer context and swap chain ids in an error.
+    fn with_ids(&self, err: Error) -> Error {
+        err.with_context_id(self.context_id)
+            .with_formatted_swap_chain_id(self.swap_chain_id.as_deref())
+    }
+
+    // Destroy the surfaces recycled after the swap chain was destroyed.
+    // Surfaces which cannot be destroyed are kept for the next time.
     // Called by the producer.
     // Returns an error if `context` is not the producer context for this swap chain.
-    fn destroy(&mut self, device: &mut Device, context: &mut Device::Context) -> Result<(), Error> {
-        self.validate_context(device, context)?;
-        let surfaces = self
-            .pending_surface
-            .take()
-            .into_iter()
-            .chain(self.back_buffer.take_surface(device, context).into_iter())
-            .chain(self.recycled_surfaces.drain(..));
-        for mut surface in surfaces {
-            device.destroy_surface(context, &mut surface)?;
+    fn collect_garbage(
+        &mut self,
+        device: &mut Device,
+        context: &mut Device::Context,
+    ) -> Result<(), Error> {
+        if self.context_id != device.context_id(context) {
+            return Err(ErrorKind::IncompatibleContext.into());
+        }
+        while let Some(mut surface) = self.orphans.pop() {
+            debug!(
+                "Destroying orphaned surface for context {:?}",
+                self.context_id
+            );
+            if let Err(err) = device.destroy_surface(context, &mut surface) {
+                self.orphans.push(surface);
+                return Err(err.into());
+            }
         }
         Ok(())
     }
 }
 
 /// A thread-safe swap chain.
-pub struct SwapChain<Device: DeviceAPI>(Arc<Mutex<SwapChainData<Device>>>);
+pub struct SwapChain<Device: DeviceAPI, Metadata = ()>(Arc<SwapChainShared<Device, Metadata>>);
+
+// The state shared by all the clones of a swap chain.
+struct SwapChainShared<Device: DeviceAPI, Metadata> {
+    // The swap chain data
+    data: Mutex<SwapChainData<Device, Metadata>>,
+    // Notified when the producer swaps buffers
+    swapped: Condvar,
+    // Notified when a consumer takes a front buffer
+    taken: Condvar,
+}
 
 // We can't derive Clone unfortunately
-impl<Device: DeviceAPI> Clone for SwapChain<Device> {
+impl<Device: DeviceAPI, Metadata> Clone for SwapChain<Device, Metadata> {
     fn clone(&self) -> Self {
         SwapChain(self.0.clone())
     }
 }
 
-impl<Device: DeviceAPI> SwapChain<Device> {
+impl<Device: DeviceAPI, Metadata> SwapChain<Device, Metadata> {
     // Guarantee unique access to the swap chain data
-    fn lock(&self) -> MutexGuard<SwapChainData<Device>> {
-        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    fn lock(&self) -> MutexGuard<'_, SwapChainData<Device, Metadata>> {
+        self.0.data.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // Run a producer operation on the swap chain data,
+    // recording the producer context and swap chain ids in any error.
+    fn produce<T>(
+        &self,
+        op: impl FnOnce(&mut SwapChainData<Device, Metadata>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut data = self.lock();
+        let result = op(&mut data);
+        result.map_err(|err| data.with_ids(err))
+    }
+
+    // Run a consumer operation on the swap chain data,
+    // waking a producer waiting for room in the queue.
+    fn consume<T>(&self, op: impl FnOnce(&mut SwapChainData<Device, Metadata>) -> T) -> T {
+        let result = op(&mut self.lock());
+        self.0.taken.notify_all();
+        result
+    }
+
+    // Wait for the producer to swap buffers or destroy the swap chain, for up to `timeout` if it is given,
+    // then run a consumer operation on the swap chain data.
+    fn wait_to_consume<T>(
+        &self,
+        timeout: Option<Duration>,
+        op: impl FnOnce(&mut SwapChainData<Device, Metadata>) -> Option<T>,
+    ) -> Option<T> {
+        let waiting = |data: &mut SwapChainData<Device, Metadata>| {
+            !data.has_pending_surface() && !data.destroyed
+        };
+        let data = self.lock();
+        let mut data = match timeout {
+            Some(timeout) => {
+                self.0
+                    .swapped
+                    .wait_timeout_while(data, timeout, waiting)
+                    .unwrap_or_else(|err| err.into_inner())
+                    .0
+            }
+            None => self
+                .0
+                .swapped
+                .wait_while(data, waiting)
+                .unwrap_or_else(|err| err.into_inner()),
+        };
+        let result = op(&mut data);
+        drop(data);
+        self.0.taken.notify_all();
+        result
+    }
+
+    // Poll for a front buffer taken by a consumer operation on the swap chain data,
+    // registering the task to be woken if there is none yet.
+    #[cfg(feature = "async")]
+    fn poll_to_consume<T>(
+        &self,
+        cx: &mut task::Context<'_>,
+        op: impl FnOnce(&mut SwapChainData<Device, Metadata>) -> Option<T>,
+    ) -> Poll<Option<T>> {
+        let mut data = self.lock();
+        match op(&mut data) {
+            Some(result) => {
+                drop(data);
+                self.0.taken.notify_all();
+                Poll::Ready(Some(result))
+            }
+            None if data.destroyed => Poll::Ready(None),
+            None => {
+                data.register_waker(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+
+    // Wake every thread waiting on the swap chain, so they notice it has been destroyed.
+    fn wake_all(&self) {
+        self.0.swapped.notify_all();
+        self.0.taken.notify_all();
+    }
+
+    // Is the swap chain referenced anywhere else?
+    fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.0) > 1
+    }
+
+    // Does the swap chain have orphaned surfaces waiting to be destroyed?
+    fn has_orphans(&self) -> bool {
+        !self.lock().orphans.is_empty()
+    }
+
+    // Wrap up the swap chain data
+    fn new(data: SwapChainData<Device, Metadata>) -> SwapChain<Device, Metadata> {
+        SwapChain(Arc::new(SwapChainShared {
+            data: Mutex::new(data),
+            swapped: Condvar::new(),
+            taken: Condvar::new(),
+        }))
     }
 
     /// Swap the back and front buffers.
+    /// Returns the id of the frame that was presented.
     /// Called by the producer.
     /// Returns an error if `context` is not the producer context for this swap chain.
+    /// Returns an error if the present mode is `Fifo` and the queue is full.
     pub fn swap_buffers(
         &self,
         device: &mut Device,
         context: &mut Device::Context,
         preserve_buffer: PreserveBuffer<'_>,
-    ) -> Result<(), Error> {
-        self.lock().swap_buffers(device, context, preserve_buffer)
+    ) -> Result<FrameId, Error>
+    where
+        Metadata: Default,
+    {
+        self.swap_buffers_with_metadata(device, context, preserve_buffer, Metadata::default())
+    }
+
+    /// Swap the back and front buffers, attaching `metadata` to the new front buffer.
+    /// The metadata is given to the consumer along with the front buffer,
+    /// and is dropped if the front buffer is recycled without being taken.
+    /// Returns the id of the frame that was presented.
+    /// Called by the producer.
+    /// Returns an error if `context` is not the producer context for this swap chain.
+    /// Returns an error if the present mode is `Fifo` and the queue is full.
+    pub fn swap_buffers_with_metadata(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        preserve_buffer: PreserveBuffer<'_>,
+        metadata: Metadata,
+    ) -> Result<FrameId, Error> {
+        let options = SwapOptions {
+            preserve_buffer,
+            metadata,
+            damage: None,
+            gl: None,
+        };
+        self.swap_buffers_with(device, context, options)
+    }
+
+    /// Swap the back and front buffers, with the given options.
+    /// Returns the id of the frame that was presented.
+    /// Called by the producer.
+    /// Returns an error if `context` is not the producer context for this swap chain.
+    /// Returns an error if the present mode is `Fifo` and the queue is full.
+    /// Returns an error if the swap chain uses fence synchronization, invalidation or multisampling
+    /// and no GL bindings are given.
+    /// Returns an error if the multisampled render target cannot be resolved.
+    /// If the swap chain was resized since the producer last drew to the multisampled render target,
+    /// the region it has in common with the back buffer is resolved, and the target is then resized.
+    /// Returns an error if the buffers to preserve are invalid, or the surfaces do not have them.
+    /// Returns a `PreserveFailed` error if preserving the back buffer fails, in which case the buffers
+    /// are still swapped, the error has the id of the presented frame,
+    /// and the back buffer age reflects the unpreserved contents.
+    /// Otherwise, if an error is returned the swap chain is left as it was.
+    pub fn swap_buffers_with(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        options: SwapOptions<'_, Metadata>,
+    ) -> Result<FrameId, Error> {
+        let result = self.produce(|data| data.swap_buffers(device, context, options));
+        // Consumers are woken even if preserving the back buffer failed, since a frame was still presented
+        self.0.swapped.notify_all();
+        result
+    }
+
+    /// Swap the back and front buffers, with the given options.
+    /// If the present mode is `Fifo` and the queue is full, this waits for
+    /// a consumer to take a front buffer, for up to `timeout`.
+    /// Returns the id of the frame that was presented.
+    /// Called by the producer.
+    /// Returns an error if the queue is still full after `timeout`.
+    /// Otherwise, returns the same errors as `swap_buffers_with`.
+    pub fn swap_buffers_timeout(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        options: SwapOptions<'_, Metadata>,
+        timeout: Duration,
+    ) -> Result<FrameId, Error> {
+        let (mut data, _) = self
+            .0
+            .taken
+            .wait_timeout_while(self.lock(), timeout, |data| data.is_queue_full())
+            .unwrap_or_else(|err| err.into_inner());
+        let result = data.swap_buffers(device, context, options);
+        let result = result.map_err(|err| data.with_ids(err));
+        drop(data);
+        self.0.swapped.notify_all();
+        result
     }
 
     /// Swap the attached swap chain.
     /// Called by the producer.
     /// Returns an error if `context` is not the producer context for both swap chains.
     /// Returns an error if this swap chain is attached, or the other swap chain is detached.
+    /// If an error is returned, both swap chains are left as they were.
     pub fn take_attachment_from(
         &self,
         device: &mut Device,
         context: &mut Device::Context,
-        other: &SwapChain<Device>,
+        other: &SwapChain<Device, Metadata>,
     ) -> Result<(), Error> {
-        self.lock()
-            .take_attachment_from(device, context, &mut *other.lock())
+        self.produce(|data| data.take_attachment_from(device, context, &mut *other.lock()))
     }
 
     /// Resize the swap chain.
     /// This creates a new back buffer of the appropriate size,
     /// and destroys the old one.
+    /// If the swap chain has pre-warmed surfaces, new ones of the appropriate size are allocated.
+    /// A multisampled framebuffer is resized, discarding its contents,
+    /// by the next call with GL bindings, such as `multisampled_framebuffer` or `swap_buffers_with`.
     /// Called by the producer.
     /// Returns an error if `context` is not the producer context for this swap chain.
+    /// If an error is returned, the swap chain is left as it was.
     pub fn resize(
         &self,
         device: &mut Device,
         context: &mut Device::Context,
         size: Size2D<i32>,
     ) -> Result<(), Error> {
-        self.lock().resize(device, context, size)
+        self.produce(|data| data.resize(device, context, size, None))
+    }
+
+    /// Resize the swap chain, preserving its contents.
+    /// This creates a new back buffer of the appropriate size,
+    /// blits the old back buffer to it, positioned according to `anchor`,
+    /// and destroys the old one.
+    /// The `filter` is used when the contents are scaled, and should be `gl::NEAREST` or `gl::LINEAR`.
+    /// Depth and stencil buffers are only preserved with `gl::NEAREST`,
+    /// and only if the surfaces have them.
+    /// Called by the producer.
+    /// Returns an error if `context` is not the producer context for this swap chain.
+    /// Returns an error if `size` is smaller than (1, 1).
+    /// Returns an error if `filter` is invalid, or copying the contents fails.
+    /// A multisampled framebuffer is resized, and the preserved contents are copied into it.
+    /// Returns an error if the swap chain has a multisampled framebuffer and `gl` is for OpenGL ES,
+    /// which cannot copy into multisampled framebuffers.
+    /// Returns an error if copying the contents into the multisampled framebuffer fails,
+    /// in which case the swap chain is still resized.
+    /// Otherwise, if an error is returned the swap chain is left as it was.
+    pub fn resize_preserving(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        gl: &Gl,
+        size: Size2D<i32>,
+        anchor: ResizeAnchor,
+        filter: GLenum,
+    ) -> Result<(), Error> {
+        self.produce(|data| data.resize(device, context, size, Some((gl, anchor, filter))))
     }
 
     /// Get the current size.
@@ -546,6 +2810,39 @@ impl<Device: DeviceAPI> SwapChain<Device> {
         self.lock().size()
     }
 
+    /// Get the number of frames since the contents of the back buffer were presented,
+    /// in the style of `EGL_EXT_buffer_age`.
+    /// An age of 1 means the back buffer holds the most recent frame, 2 the frame before that, and so on.
+    /// An age of 0 means the contents of the back buffer are undefined,
+    /// for example because it was newly allocated or the swap chain was resized.
+    /// Called by the producer.
+    pub fn back_buffer_age(&self) -> u64 {
+        self.lock().back_buffer_age()
+    }
+
+    /// Get the most recent size requested by a consumer, if it differs from the current size.
+    /// Called by the producer.
+    pub fn pending_resize(&self) -> Option<Size2D<i32>> {
+        self.lock().pending_resize()
+    }
+
+    /// Get the presentation policy.
+    pub fn present_mode(&self) -> PresentMode {
+        self.lock().present_mode()
+    }
+
+    /// Get the number of frames presented, taken and dropped so far.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.lock().frame_stats()
+    }
+
+    /// Get the feedback for the most recent frame a consumer displayed.
+    /// Returns `None` if no consumer has sent feedback yet.
+    /// Called by the producer.
+    pub fn presentation_feedback(&self) -> Option<PresentationFeedback> {
+        self.lock().presentation_feedback()
+    }
+
     /// Take the current back buffer.
     /// Called by a producer.
     pub fn take_surface_texture(
@@ -553,7 +2850,7 @@ impl<Device: DeviceAPI> SwapChain<Device> {
         device: &Device,
         context: &mut Device::Context,
     ) -> Result<Device::SurfaceTexture, Error> {
-        self.lock().take_surface_texture(device, context)
+        self.produce(|data| data.take_surface_texture(device, context))
     }
 
     /// Recycle the current back buffer.
@@ -564,18 +2861,90 @@ impl<Device: DeviceAPI> SwapChain<Device> {
         context: &mut Device::Context,
         surface_texture: Device::SurfaceTexture,
     ) -> Result<(), Error> {
-        self.lock()
-            .recycle_surface_texture(device, context, surface_texture)
+        self.produce(|data| data.recycle_surface_texture(device, context, surface_texture))
     }
 
-    /// Take the current front buffer.
+    /// Take the oldest front buffer.
     /// Returns `None` if there is no current front buffer.
     /// Called by a consumer.
     pub fn take_pending_surface(&self) -> Option<Device::Surface> {
-        self.lock().take_pending_surface()
+        self.consume(|data| data.take_pending_surface())
+    }
+
+    /// Take the oldest front buffer, together with the fence signalled when the producer finished drawing it.
+    /// The consumer should wait for the fence before reading the surface, and is responsible for deleting it.
+    /// The fence is `None` unless the swap chain uses fence synchronization.
+    /// Returns `None` if there is no current front buffer.
+    /// Called by a consumer.
+    pub fn take_frame_with_fence(&self) -> Option<FencedFrame<Device::Surface, Metadata>> {
+        self.consume(|data| data.take_frame_with_fence())
+    }
+
+    /// Recycle the current front buffer, once `fence` is signalled.
+    /// The producer waits for the fence before drawing to the surface again, then deletes it.
+    /// Called by a consumer.
+    pub fn recycle_surface_with_fence(&self, surface: Device::Surface, fence: Fence) {
+        self.lock().recycle_surface_with_fence(surface, fence)
+    }
+
+    /// Take the oldest front buffer, waiting for the producer to swap buffers if necessary.
+    /// Returns `None` if there is still no front buffer after `timeout`,
+    /// or the swap chain is destroyed.
+    /// Called by a consumer.
+    pub fn take_surface_timeout(&self, timeout: Duration) -> Option<Device::Surface> {
+        self.wait_to_consume(Some(timeout), |data| data.take_pending_surface())
+    }
+
+    /// Take the oldest front buffer, together with the fence signalled when the producer finished drawing it,
+    /// waiting for the producer to swap buffers if necessary.
+    /// Returns `None` if there is still no front buffer after `timeout`,
+    /// or the swap chain is destroyed.
+    /// Called by a consumer.
+    pub fn take_frame_with_fence_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Option<FencedFrame<Device::Surface, Metadata>> {
+        self.wait_to_consume(Some(timeout), |data| data.take_frame_with_fence())
+    }
+
+    /// Take the oldest front buffer, waiting for the producer to swap buffers if necessary.
+    /// Returns `None` if the swap chain is destroyed.
+    /// Called by a consumer.
+    /// This blocks the current thread, `next_frame` is the asynchronous equivalent.
+    pub fn wait_for_frame(&self) -> Option<Device::Surface> {
+        self.wait_to_consume(None, |data| data.take_pending_surface())
+    }
+
+    /// Take the oldest front buffer, together with the fence signalled when the producer finished drawing it,
+    /// waiting for the producer to swap buffers if necessary.
+    /// Returns `None` if the swap chain is destroyed.
+    /// Called by a consumer.
+    /// This blocks the current thread, `next_frame_with_fence` is the asynchronous equivalent.
+    pub fn wait_for_frame_with_fence(&self) -> Option<FencedFrame<Device::Surface, Metadata>> {
+        self.wait_to_consume(None, |data| data.take_frame_with_fence())
+    }
+
+    /// A future which takes the oldest front buffer, once the producer has swapped buffers.
+    /// The future resolves to `None` if the swap chain is destroyed.
+    /// Called by a consumer.
+    #[cfg(feature = "async")]
+    pub fn next_frame(&self) -> NextFrame<Device, Metadata> {
+        NextFrame(self.clone())
+    }
+
+    /// A future which takes the oldest front buffer, together with the fence signalled
+    /// when the producer finished drawing it, once the producer has swapped buffers.
+    /// The future resolves to `None` if the swap chain is destroyed.
+    /// Called by a consumer.
+    #[cfg(feature = "async")]
+    pub fn next_frame_with_fence(&self) -> NextFencedFrame<Device, Metadata> {
+        NextFencedFrame(self.clone())
     }
 
     /// Clear the current back buffer.
+    /// The back buffer of a detached swap chain is cleared without changing
+    /// the current surface of the context, unless the backend (such as ANGLE)
+    /// only gives surfaces a framebuffer object while they are bound.
     /// Called by the producer.
     /// Returns an error if `context` is not the producer context for this swap chain.
     pub fn clear_surface(
@@ -585,7 +2954,27 @@ impl<Device: DeviceAPI> SwapChain<Device> {
         gl: &Gl,
         color: [f32; 4],
     ) -> Result<(), Error> {
-        self.lock().clear_surface(device, context, gl, color)
+        let options = ClearOptions {
+            color: Some(color),
+            ..ClearOptions::default()
+        };
+        self.produce(|data| data.clear_surface(device, context, gl, &options))
+    }
+
+    /// Clear the current back buffer, with the given options.
+    /// The buffers and region to clear are given by the options,
+    /// and the rest of the back buffer is left unchanged.
+    /// The producer's GL state is restored afterwards.
+    /// Called by the producer.
+    /// Returns an error if `context` is not the producer context for this swap chain.
+    pub fn clear_surface_with(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        gl: &Gl,
+        options: ClearOptions,
+    ) -> Result<(), Error> {
+        self.produce(|data| data.clear_surface(device, context, gl, &options))
     }
 
     /// Is this the attached swap chain?
@@ -593,11 +2982,99 @@ impl<Device: DeviceAPI> SwapChain<Device> {
         self.lock().is_attached()
     }
 
+    /// Has this swap chain been destroyed?
+    pub fn is_destroyed(&self) -> bool {
+        self.lock().destroyed
+    }
+
+    /// Repair a swap chain whose back buffer is missing, for example because
+    /// a surface texture taken with `take_surface_texture` was never returned,
+    /// or because an earlier operation failed to restore it.
+    /// A new back buffer of the current size is allocated,
+    /// and attached to the producer context if the swap chain is attached.
+    /// Swap chains which are not missing a back buffer are left unchanged.
+    /// Returns a report of what was lost.
+    /// Called by the producer.
+    /// Returns an error if `context` is not the producer context for this swap chain.
+    /// Returns an error if the new back buffer cannot be created.
+    pub fn repair(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+    ) -> Result<RepairReport, Error> {
+        self.produce(|data| data.repair(device, context))
+    }
+
+    /// Get the multisampled framebuffer the producer should draw to,
+    /// or `None` if the swap chain was not created with multisampling.
+    /// The framebuffer is created by the first call, and resized to match the swap chain by later calls.
+    /// Its contents are discarded when the swap chain is resized.
+    /// Called by the producer.
+    /// Returns an error if `context` is not the producer context for this swap chain.
+    /// Returns an error if the framebuffer cannot be created.
+    pub fn multisampled_framebuffer(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        gl: &Gl,
+    ) -> Result<Option<GLuint>, Error> {
+        self.produce(|data| data.multisampled_framebuffer(device, context, gl))
+    }
+
+    /// Delete the multisampled framebuffer, if it has been created.
+    /// It is recreated by the next call to `multisampled_framebuffer`.
+    /// Called by the producer.
+    /// Returns an error if `context` is not the producer context for this swap chain.
+    pub fn destroy_multisampled_framebuffer(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        gl: &Gl,
+    ) -> Result<(), Error> {
+        self.produce(|data| data.destroy_multisampled_framebuffer(device, context, gl))
+    }
+
     /// Destroy the swap chain.
+    /// A multisampled framebuffer is deleted by the next producer call with GL bindings
+    /// on another swap chain in the same `SwapChains` collection.
+    /// A swap chain which is not in a collection leaves it to be deleted along with the producer context,
+    /// unless it was deleted by `destroy_multisampled_framebuffer` first,
+    /// or the swap chain is destroyed with `destroy_with_gl`.
+    /// Surfaces recycled by consumers after the swap chain is destroyed are kept
+    /// until the producer calls `collect_garbage`, or destroys the swap chain again.
+    /// Other producer calls on a destroyed swap chain return an error.
     /// Called by the producer.
     /// Returns an error if `context` is not the producer context for this swap chain.
     pub fn destroy(&self, device: &mut Device, context: &mut Device::Context) -> Result<(), Error> {
-        self.lock().destroy(device, context)
+        let result = self.produce(|data| data.destroy(device, context, None));
+        self.wake_all();
+        result
+    }
+
+    /// Destroy the swap chain, deleting its multisampled framebuffer if it has one.
+    /// Otherwise the same as `destroy`.
+    /// Called by the producer.
+    /// Returns an error if `context` is not the producer context for this swap chain.
+    pub fn destroy_with_gl(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+        gl: &Gl,
+    ) -> Result<(), Error> {
+        let result = self.produce(|data| data.destroy(device, context, Some(gl)));
+        self.wake_all();
+        result
+    }
+
+    /// Destroy the surfaces recycled by consumers after the swap chain was destroyed.
+    /// Called by the producer.
+    /// Returns an error if `context` is not the producer context for this swap chain.
+    pub fn collect_garbage(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+    ) -> Result<(), Error> {
+        self.produce(|data| data.collect_garbage(device, context))
     }
 
     /// Create a new attached swap chain
@@ -605,16 +3082,26 @@ impl<Device: DeviceAPI> SwapChain<Device> {
         device: &mut Device,
         context: &mut Device::Context,
         surface_access: SurfaceAccess,
-    ) -> Result<SwapChain<Device>, Error> {
-        let size = device.context_surface_info(context).unwrap().unwrap().size;
-        Ok(SwapChain(Arc::new(Mutex::new(SwapChainData {
-            size,
-            context_id: device.context_id(context),
+    ) -> Result<SwapChain<Device, Metadata>, Error> {
+        SwapChain::create_attached_with_options(
+            device,
+            context,
             surface_access,
-            back_buffer: BackBuffer::Attached,
-            pending_surface: None,
-            recycled_surfaces: Vec::new(),
-        }))))
+            SwapChainOptions::default(),
+        )
+    }
+
+    /// Create a new attached swap chain with the given options
+    pub fn create_attached_with_options(
+        device: &mut Device,
+        context: &mut Device::Context,
+        surface_access: SurfaceAccess,
+        options: SwapChainOptions,
+    ) -> Result<SwapChain<Device, Metadata>, Error> {
+        let context_id = device.context_id(context);
+        SwapChainData::create_attached(device, context, surface_access, options)
+            .map(SwapChain::new)
+            .map_err(|err| err.with_context_id(context_id))
     }
 
     /// Create a new detached swap chain
@@ -623,32 +3110,87 @@ impl<Device: DeviceAPI> SwapChain<Device> {
         context: &mut Device::Context,
         surface_access: SurfaceAccess,
         size: Size2D<i32>,
-    ) -> Result<SwapChain<Device>, Error> {
-        let surface_type = SurfaceType::Generic { size };
-        let surface = device.create_surface(context, surface_access, surface_type)?;
-        Ok(SwapChain(Arc::new(Mutex::new(SwapChainData {
-            size,
-            context_id: device.context_id(context),
+    ) -> Result<SwapChain<Device, Metadata>, Error> {
+        SwapChain::create_detached_with_options(
+            device,
+            context,
             surface_access,
-            back_buffer: BackBuffer::Detached(surface),
-            pending_surface: None,
-            recycled_surfaces: Vec::new(),
-        }))))
+            size,
+            SwapChainOptions::default(),
+        )
+    }
+
+    /// Create a new detached swap chain with the given options
+    pub fn create_detached_with_options(
+        device: &mut Device,
+        context: &mut Device::Context,
+        surface_access: SurfaceAccess,
+        size: Size2D<i32>,
+        options: SwapChainOptions,
+    ) -> Result<SwapChain<Device, Metadata>, Error> {
+        let context_id = device.context_id(context);
+        SwapChainData::create_detached(device, context, surface_access, size, options)
+            .map(SwapChain::new)
+            .map_err(|err| err.with_context_id(context_id))
+    }
+}
+
+/// A future which resolves to the oldest front buffer of a swap chain, or `None` if it is destroyed.
+/// Returned by `SwapChain::next_frame`.
+#[cfg(feature = "async")]
+pub struct NextFrame<Device: DeviceAPI, Metadata>(SwapChain<Device, Metadata>);
+
+#[cfg(feature = "async")]
+impl<Device: DeviceAPI, Metadata> Future for NextFrame<Device, Metadata> {
+    type Output = Option<Device::Surface>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Device::Surface>> {
+        self.0
+            .poll_to_consume(cx, |data| data.take_pending_surface())
+    }
+}
+
+/// A future which resolves to the oldest front buffer of a swap chain,
+/// together with the fence signalled when the producer finished drawing it,
+/// or `None` if the swap chain is destroyed.
+/// Returned by `SwapChain::next_frame_with_fence`.
+#[cfg(feature = "async")]
+pub struct NextFencedFrame<Device: DeviceAPI, Metadata>(SwapChain<Device, Metadata>);
+
+#[cfg(feature = "async")]
+impl<Device: DeviceAPI, Metadata> Future for NextFencedFrame<Device, Metadata> {
+    type Output = Option<FencedFrame<Device::Surface, Metadata>>;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<FencedFrame<Device::Surface, Metadata>>> {
+        self.0
+            .poll_to_consume(cx, |data| data.take_frame_with_fence())
     }
 }
 
-impl<Device> SwapChainAPI for SwapChain<Device>
+impl<Device, Metadata> SwapChainAPI for SwapChain<Device, Metadata>
 where
     Device: 'static + DeviceAPI,
     Device::Surface: Send,
+    Metadata: 'static + Send,
 {
     type Surface = Device::Surface;
+    type Metadata = Metadata;
 
-    /// Take the current front buffer.
+    /// Take the oldest front buffer.
     /// Returns the most recent recycled surface if there is no current front buffer.
     /// Called by a consumer.
     fn take_surface(&self) -> Option<Device::Surface> {
-        self.lock().take_surface()
+        self.consume(|data| data.take_surface())
+    }
+
+    /// Take the oldest front buffer, together with its frame id and metadata.
+    /// Returns `None` if there is no current front buffer.
+    /// Called by a consumer.
+    fn take_frame(&self) -> Option<Frame<Device::Surface, Metadata>> {
+        self.consume(|data| data.take_frame())
     }
 
     /// Recycle the current front buffer.
@@ -656,52 +3198,138 @@ where
     fn recycle_surface(&self, surface: Device::Surface) {
         self.lock().recycle_surface(surface)
     }
+
+    /// Tell the producer that the frame `frame_id` was displayed at `timestamp`.
+    /// Called by a consumer.
+    fn present_feedback(&self, frame_id: FrameId, timestamp: Instant) {
+        self.lock().present_feedback(frame_id, timestamp)
+    }
+
+    /// Ask the producer to resize the swap chain.
+    /// Requests are coalesced, so only the most recent one is acted on.
+    /// Called by a consumer.
+    fn request_resize(&self, size: Size2D<i32>) {
+        self.lock().request_resize(size)
+    }
+
+    /// The current epoch of the swap chain.
+    /// Frames from an earlier epoch were drawn before the most recent resize.
+    /// Called by a consumer.
+    fn epoch(&self) -> Epoch {
+        self.lock().epoch()
+    }
 }
 
 /// A thread-safe collection of swap chains.
 #[derive(Default)]
-pub struct SwapChains<SwapChainID: Eq + Hash, Device: DeviceAPI> {
+pub struct SwapChains<SwapChainID: Eq + Hash, Device: DeviceAPI, Metadata = ()> {
     // The swap chain ids, indexed by context id
     ids: Arc<Mutex<FnvHashMap<ContextID, FnvHashSet<SwapChainID>>>>,
     // The swap chains, indexed by swap chain id
-    table: Arc<RwLock<FnvHashMap<SwapChainID, SwapChain<Device>>>>,
+    table: Arc<RwLock<FnvHashMap<SwapChainID, SwapChain<Device, Metadata>>>>,
+    // The destroyed swap chains which may still have surfaces recycled into them, indexed by context id
+    destroyed: Arc<Mutex<DestroyedSwapChains<SwapChainID, Device, Metadata>>>,
+    // The GL objects left by destroyed swap chains, shared with every swap chain in the collection
+    gl_garbage: GlGarbage,
 }
 
+// The destroyed swap chains for each producer context, with their ids.
+type DestroyedSwapChains<SwapChainID, Device, Metadata> =
+    FnvHashMap<ContextID, Vec<(SwapChainID, SwapChain<Device, Metadata>)>>;
+
 // We can't derive Clone unfortunately
-impl<SwapChainID: Eq + Hash, Device: DeviceAPI> Clone for SwapChains<SwapChainID, Device> {
+impl<SwapChainID: Eq + Hash, Device: DeviceAPI, Metadata> Clone
+    for SwapChains<SwapChainID, Device, Metadata>
+{
     fn clone(&self) -> Self {
         SwapChains {
             ids: self.ids.clone(),
             table: self.table.clone(),
+            destroyed: self.destroyed.clone(),
+            gl_garbage: self.gl_garbage.clone(),
         }
     }
 }
 
-impl<SwapChainID, Device> SwapChains<SwapChainID, Device>
+impl<SwapChainID, Device, Metadata> SwapChains<SwapChainID, Device, Metadata>
 where
     SwapChainID: Clone + Eq + Hash + Debug,
     Device: DeviceAPI,
 {
     /// Create a new collection.
-    pub fn new() -> SwapChains<SwapChainID, Device> {
+    pub fn new() -> SwapChains<SwapChainID, Device, Metadata> {
         SwapChains {
             ids: Arc::new(Mutex::new(FnvHashMap::default())),
             table: Arc::new(RwLock::new(FnvHashMap::default())),
+            destroyed: Arc::new(Mutex::new(FnvHashMap::default())),
+            gl_garbage: GlGarbage::default(),
         }
     }
 
+    // Give a swap chain created for the collection its id,
+    // and share the GL garbage of the collection with it.
+    fn adopt(
+        &self,
+        id: &SwapChainID,
+        swap_chain: SwapChain<Device, Metadata>,
+    ) -> SwapChain<Device, Metadata> {
+        let mut data = swap_chain.lock();
+        data.swap_chain_id = Some(format!("{:?}", id));
+        data.gl_garbage = self.gl_garbage.clone();
+        drop(data);
+        swap_chain
+    }
+
     // Lock the ids
-    fn ids(&self) -> MutexGuard<FnvHashMap<ContextID, FnvHashSet<SwapChainID>>> {
+    fn ids(&self) -> MutexGuard<'_, FnvHashMap<ContextID, FnvHashSet<SwapChainID>>> {
         self.ids.lock().unwrap_or_else(|err| err.into_inner())
     }
 
+    // Lock the destroyed swap chains
+    fn destroyed(&self) -> MutexGuard<'_, DestroyedSwapChains<SwapChainID, Device, Metadata>> {
+        self.destroyed.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // Collect the garbage for a producer context, logging any failure.
+    fn collect_garbage_quietly(&self, device: &mut Device, context: &mut Device::Context) {
+        if let Err(err) = self.collect_garbage(device, context) {
+            debug!("Failed to collect garbage ({:?})", err);
+        }
+    }
+
+    // Destroy a swap chain which has been removed from the table.
+    // If it is still referenced by consumers, it is kept so their recycled surfaces can be destroyed.
+    // If it cannot be destroyed, it is put back in the table.
+    fn destroy_swap_chain(
+        &self,
+        id: SwapChainID,
+        swap_chain: SwapChain<Device, Metadata>,
+        device: &mut Device,
+        context: &mut Device::Context,
+    ) -> Result<(), Error> {
+        let result = swap_chain
+            .destroy(device, context)
+            .map_err(|err| err.with_swap_chain_id(&id));
+        if !swap_chain.is_destroyed() {
+            self.table_mut().insert(id, swap_chain);
+        } else if swap_chain.is_shared() || swap_chain.has_orphans() {
+            self.destroyed()
+                .entry(device.context_id(context))
+                .or_default()
+                .push((id, swap_chain));
+        }
+        result
+    }
+
     // Lock the lookup table
-    fn table(&self) -> RwLockReadGuard<FnvHashMap<SwapChainID, SwapChain<Device>>> {
+    fn table(&self) -> RwLockReadGuard<'_, FnvHashMap<SwapChainID, SwapChain<Device, Metadata>>> {
         self.table.read().unwrap_or_else(|err| err.into_inner())
     }
 
     // Lock the lookup table for writing
-    fn table_mut(&self) -> RwLockWriteGuard<FnvHashMap<SwapChainID, SwapChain<Device>>> {
+    fn table_mut(
+        &self,
+    ) -> RwLockWriteGuard<'_, FnvHashMap<SwapChainID, SwapChain<Device, Metadata>>> {
         self.table.write().unwrap_or_else(|err| err.into_inner())
     }
 
@@ -714,15 +3342,46 @@ where
         context: &mut Device::Context,
         surface_access: SurfaceAccess,
     ) -> Result<(), Error> {
+        self.create_attached_swap_chain_with_options(
+            id,
+            device,
+            context,
+            surface_access,
+            SwapChainOptions::default(),
+        )
+    }
+
+    /// Create a new attached swap chain with the given options and insert it in the table.
+    /// Returns an error if the `id` is already in the table.
+    pub fn create_attached_swap_chain_with_options(
+        &self,
+        id: SwapChainID,
+        device: &mut Device,
+        context: &mut Device::Context,
+        surface_access: SurfaceAccess,
+        options: SwapChainOptions,
+    ) -> Result<(), Error> {
+        self.collect_garbage_quietly(device, context);
         match self.table_mut().entry(id.clone()) {
-            Entry::Occupied(_) => Err(Error::Failed)?,
-            Entry::Vacant(entry) => {
-                entry.insert(SwapChain::create_attached(device, context, surface_access)?)
-            }
+            Entry::Occupied(_) => Err(Error::new(ErrorKind::DuplicateSwapChain)
+                .with_context_id(device.context_id(context))
+                .with_swap_chain_id(&id))?,
+            Entry::Vacant(entry) => entry.insert(
+                self.adopt(
+                    &id,
+                    SwapChain::create_attached_with_options(
+                        device,
+                        context,
+                        surface_access,
+                        options,
+                    )
+                    .map_err(|err| err.with_swap_chain_id(&id))?,
+                ),
+            ),
         };
         self.ids()
             .entry(device.context_id(context))
-            .or_insert_with(Default::default)
+            .or_default()
             .insert(id);
         Ok(())
     }
@@ -737,18 +3396,49 @@ where
         context: &mut Device::Context,
         surface_access: SurfaceAccess,
     ) -> Result<(), Error> {
+        self.create_detached_swap_chain_with_options(
+            id,
+            size,
+            device,
+            context,
+            surface_access,
+            SwapChainOptions::default(),
+        )
+    }
+
+    /// Create a new dettached swap chain with the given options and insert it in the table.
+    /// Returns an error if the `id` is already in the table.
+    pub fn create_detached_swap_chain_with_options(
+        &self,
+        id: SwapChainID,
+        size: Size2D<i32>,
+        device: &mut Device,
+        context: &mut Device::Context,
+        surface_access: SurfaceAccess,
+        options: SwapChainOptions,
+    ) -> Result<(), Error> {
+        self.collect_garbage_quietly(device, context);
         match self.table_mut().entry(id.clone()) {
-            Entry::Occupied(_) => Err(Error::Failed)?,
-            Entry::Vacant(entry) => entry.insert(SwapChain::create_detached(
-                device,
-                context,
-                surface_access,
-                size,
-            )?),
+            Entry::Occupied(_) => Err(Error::new(ErrorKind::DuplicateSwapChain)
+                .with_context_id(device.context_id(context))
+                .with_swap_chain_id(&id))?,
+            Entry::Vacant(entry) => entry.insert(
+                self.adopt(
+                    &id,
+                    SwapChain::create_detached_with_options(
+                        device,
+                        context,
+                        surface_access,
+                        size,
+                        options,
+                    )
+                    .map_err(|err| err.with_swap_chain_id(&id))?,
+                ),
+            ),
         };
         self.ids()
             .entry(device.context_id(context))
-            .or_insert_with(Default::default)
+            .or_default()
             .insert(id);
         Ok(())
     }
@@ -762,8 +3452,10 @@ where
         device: &mut Device,
         context: &mut Device::Context,
     ) -> Result<(), Error> {
-        if let Some(swap_chain) = self.table_mut().remove(&id) {
-            swap_chain.destroy(device, context)?;
+        self.collect_garbage_quietly(device, context);
+        let swap_chain = self.table_mut().remove(&id);
+        if let Some(swap_chain) = swap_chain {
+            self.destroy_swap_chain(id.clone(), swap_chain, device, context)?;
         }
         if let Some(ids) = self.ids().get_mut(&device.context_id(context)) {
             ids.remove(&id);
@@ -778,23 +3470,53 @@ where
         device: &mut Device,
         context: &mut Device::Context,
     ) -> Result<(), Error> {
-        if let Some(mut ids) = self.ids().remove(&device.context_id(context)) {
+        self.collect_garbage_quietly(device, context);
+        let ids = self.ids().remove(&device.context_id(context));
+        if let Some(mut ids) = ids {
             for id in ids.drain() {
-                if let Some(swap_chain) = self.table_mut().remove(&id) {
-                    swap_chain.destroy(device, context)?;
+                let swap_chain = self.table_mut().remove(&id);
+                if let Some(swap_chain) = swap_chain {
+                    self.destroy_swap_chain(id, swap_chain, device, context)?;
                 }
             }
         }
         Ok(())
     }
 
+    /// Destroy the surfaces recycled by consumers into destroyed swap chains for a particular producer context.
+    /// This is done by every other call from the producer, so is only needed by producers which
+    /// destroy their swap chains and then make no further calls.
+    /// Called by the producer.
+    pub fn collect_garbage(
+        &self,
+        device: &mut Device,
+        context: &mut Device::Context,
+    ) -> Result<(), Error> {
+        let context_id = device.context_id(context);
+        let mut destroyed = self.destroyed();
+        let mut result = Ok(());
+        if let Some(swap_chains) = destroyed.get_mut(&context_id) {
+            swap_chains.retain(|(id, swap_chain)| {
+                if let Err(err) = swap_chain.collect_garbage(device, context) {
+                    result = Err(err.with_swap_chain_id(id));
+                }
+                swap_chain.is_shared() || swap_chain.has_orphans()
+            });
+            if swap_chains.is_empty() {
+                destroyed.remove(&context_id);
+            }
+        }
+        result
+    }
+
     /// Iterate over all the swap chains for a particular producer context.
     /// Called by the producer.
     pub fn iter(
         &self,
         device: &mut Device,
         context: &mut Device::Context,
-    ) -> impl Iterator<Item = (SwapChainID, SwapChain<Device>)> {
+    ) -> impl Iterator<Item = (SwapChainID, SwapChain<Device, Metadata>)> {
+        self.collect_garbage_quietly(device, context);
         self.ids()
             .get(&device.context_id(context))
             .iter()
@@ -805,18 +3527,23 @@ where
     }
 }
 
-impl<SwapChainID, Device> SwapChainsAPI<SwapChainID> for SwapChains<SwapChainID, Device>
+impl<SwapChainID, Device, Metadata> SwapChainsAPI<SwapChainID>
+    for SwapChains<SwapChainID, Device, Metadata>
 where
     SwapChainID: 'static + Clone + Eq + Hash + Debug + Sync + Send,
     Device: 'static + DeviceAPI,
     Device::Surface: Send,
+    Metadata: 'static + Send,
 {
     type Surface = Device::Surface;
-    type SwapChain = SwapChain<Device>;
+    type SwapChain = SwapChain<Device, Metadata>;
 
     /// Get a swap chain
-    fn get(&self, id: SwapChainID) -> Option<SwapChain<Device>> {
+    fn get(&self, id: SwapChainID) -> Option<SwapChain<Device, Metadata>> {
         debug!("Getting swap chain {:?}", id);
         self.table().get(&id).cloned()
     }
 }
+
+#[cfg(test)]
+mod tests;
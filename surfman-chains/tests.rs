@@ -0,0 +1,1685 @@
+// Tests for swap chains, using a mock device whose surface operations can be made to fail.
+
+use super::*;
+
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::os::raw::c_void;
+use std::ptr;
+#[cfg(feature = "async")]
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "async")]
+use std::sync::atomic::Ordering;
+#[cfg(feature = "async")]
+use std::task::Wake;
+use std::thread;
+
+use euclid::default::Point2D;
+
+use surfman::GLApi;
+use surfman::GLVersion;
+use surfman::NativeWidget;
+use surfman::SurfaceID;
+
+// The surface operations which can be made to fail.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Call {
+    Create,
+    Bind,
+    Unbind,
+    Destroy,
+}
+
+const CALLS: [Call; 4] = [Call::Create, Call::Bind, Call::Unbind, Call::Destroy];
+
+// A surface which, like a real one, panics if it is dropped without being destroyed.
+#[derive(Debug)]
+struct MockSurface {
+    id: usize,
+    size: Size2D<i32>,
+    context_id: ContextID,
+    destroyed: bool,
+}
+
+impl Drop for MockSurface {
+    fn drop(&mut self) {
+        if !self.destroyed && !thread::panicking() {
+            panic!("Surface {} dropped without being destroyed", self.id);
+        }
+    }
+}
+
+struct MockContext {
+    id: ContextID,
+    surface: Option<MockSurface>,
+}
+
+// A device which keeps count of its live surfaces.
+#[derive(Default)]
+struct MockDevice {
+    next_id: Cell<usize>,
+    live_surfaces: Cell<usize>,
+    failures: RefCell<Vec<Call>>,
+}
+
+impl MockDevice {
+    // Make the next call of the given operation fail.
+    fn fail(&self, call: Call) {
+        self.failures.borrow_mut().push(call);
+    }
+
+    // Forget any failures which were not triggered.
+    fn recover(&self) {
+        self.failures.borrow_mut().clear();
+    }
+
+    fn check(&self, call: Call) -> Result<(), SurfmanError> {
+        let mut failures = self.failures.borrow_mut();
+        match failures.iter().position(|&failure| failure == call) {
+            Some(index) => {
+                failures.remove(index);
+                Err(SurfmanError::Failed)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn new_surface(&self, context_id: ContextID, size: Size2D<i32>) -> MockSurface {
+        let id = self.next_id.get() + 1;
+        self.next_id.set(id);
+        self.live_surfaces.set(self.live_surfaces.get() + 1);
+        MockSurface {
+            id,
+            size,
+            context_id,
+            destroyed: false,
+        }
+    }
+
+    // A context with a surface of the given size bound to it.
+    fn context(&self, size: Size2D<i32>) -> MockContext {
+        let id = ContextID(self.next_id.get() as u64);
+        MockContext {
+            id,
+            surface: Some(self.new_surface(id, size)),
+        }
+    }
+}
+
+impl DeviceAPI for MockDevice {
+    type Connection = surfman::Connection;
+    type Context = MockContext;
+    type ContextDescriptor = ();
+    type NativeContext = ();
+    type Surface = MockSurface;
+    type SurfaceTexture = MockSurface;
+
+    fn native_device(&self) -> surfman::NativeDevice {
+        unimplemented!()
+    }
+
+    fn connection(&self) -> surfman::Connection {
+        unimplemented!()
+    }
+
+    fn adapter(&self) -> surfman::Adapter {
+        unimplemented!()
+    }
+
+    fn gl_api(&self) -> GLApi {
+        GLApi::GL
+    }
+
+    fn create_context_descriptor(&self, _: &ContextAttributes) -> Result<(), SurfmanError> {
+        Ok(())
+    }
+
+    fn create_context(
+        &mut self,
+        _: &(),
+        _: Option<&MockContext>,
+    ) -> Result<MockContext, SurfmanError> {
+        unimplemented!()
+    }
+
+    unsafe fn create_context_from_native_context(
+        &self,
+        _: (),
+    ) -> Result<MockContext, SurfmanError> {
+        unimplemented!()
+    }
+
+    fn destroy_context(&self, context: &mut MockContext) -> Result<(), SurfmanError> {
+        if let Some(mut surface) = context.surface.take() {
+            self.destroy_surface(context, &mut surface)?;
+        }
+        Ok(())
+    }
+
+    fn context_descriptor(&self, _: &MockContext) {}
+
+    fn make_context_current(&self, _: &MockContext) -> Result<(), SurfmanError> {
+        Ok(())
+    }
+
+    fn make_no_context_current(&self) -> Result<(), SurfmanError> {
+        Ok(())
+    }
+
+    fn context_descriptor_attributes(&self, _: &()) -> ContextAttributes {
+        ContextAttributes {
+            version: GLVersion { major: 3, minor: 0 },
+            flags: ContextAttributeFlags::ALPHA
+                | ContextAttributeFlags::DEPTH
+                | ContextAttributeFlags::STENCIL,
+        }
+    }
+
+    fn get_proc_address(&self, _: &MockContext, _: &str) -> *const c_void {
+        ptr::null()
+    }
+
+    fn bind_surface_to_context(
+        &self,
+        context: &mut MockContext,
+        surface: MockSurface,
+    ) -> Result<(), (SurfmanError, MockSurface)> {
+        if let Err(err) = self.check(Call::Bind) {
+            return Err((err, surface));
+        }
+        if context.surface.is_some() {
+            return Err((SurfmanError::SurfaceAlreadyBound, surface));
+        }
+        context.surface = Some(surface);
+        Ok(())
+    }
+
+    fn unbind_surface_from_context(
+        &self,
+        context: &mut MockContext,
+    ) -> Result<Option<MockSurface>, SurfmanError> {
+        self.check(Call::Unbind)?;
+        Ok(context.surface.take())
+    }
+
+    fn context_id(&self, context: &MockContext) -> ContextID {
+        context.id
+    }
+
+    fn context_surface_info(
+        &self,
+        context: &MockContext,
+    ) -> Result<Option<SurfaceInfo>, SurfmanError> {
+        Ok(context
+            .surface
+            .as_ref()
+            .map(|surface| self.surface_info(surface)))
+    }
+
+    fn native_context(&self, _: &MockContext) {}
+
+    fn create_surface(
+        &mut self,
+        context: &MockContext,
+        _: SurfaceAccess,
+        surface_type: SurfaceType<NativeWidget>,
+    ) -> Result<MockSurface, SurfmanError> {
+        self.check(Call::Create)?;
+        match surface_type {
+            SurfaceType::Generic { size } => Ok(self.new_surface(context.id, size)),
+            SurfaceType::Widget { .. } => Err(SurfmanError::UnsupportedOnThisPlatform),
+        }
+    }
+
+    fn create_surface_texture(
+        &self,
+        _: &mut MockContext,
+        surface: MockSurface,
+    ) -> Result<MockSurface, (SurfmanError, MockSurface)> {
+        Ok(surface)
+    }
+
+    fn destroy_surface(
+        &self,
+        _: &mut MockContext,
+        surface: &mut MockSurface,
+    ) -> Result<(), SurfmanError> {
+        self.check(Call::Destroy)?;
+        assert!(!surface.destroyed, "Surface {} destroyed twice", surface.id);
+        surface.destroyed = true;
+        self.live_surfaces.set(self.live_surfaces.get() - 1);
+        Ok(())
+    }
+
+    fn destroy_surface_texture(
+        &self,
+        _: &mut MockContext,
+        surface_texture: MockSurface,
+    ) -> Result<MockSurface, (SurfmanError, MockSurface)> {
+        Ok(surface_texture)
+    }
+
+    fn surface_gl_texture_target(&self) -> GLenum {
+        gl::TEXTURE_2D
+    }
+
+    fn present_surface(&self, _: &MockContext, _: &mut MockSurface) -> Result<(), SurfmanError> {
+        Ok(())
+    }
+
+    fn resize_surface(
+        &self,
+        _: &MockContext,
+        _: &mut MockSurface,
+        _: Size2D<i32>,
+    ) -> Result<(), SurfmanError> {
+        unimplemented!()
+    }
+
+    fn surface_info(&self, surface: &MockSurface) -> SurfaceInfo {
+        SurfaceInfo {
+            size: surface.size,
+            id: SurfaceID(surface.id),
+            context_id: surface.context_id,
+            framebuffer_object: surface.id as GLuint,
+        }
+    }
+
+    fn surface_texture_object(&self, surface_texture: &MockSurface) -> GLuint {
+        surface_texture.id as GLuint
+    }
+}
+
+// The parts of a swap chain which a failed operation should leave as they were.
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    attached: bool,
+    back_buffer: Option<usize>,
+    pending: Vec<usize>,
+    recycled: Vec<usize>,
+    size: Size2D<i32>,
+}
+
+fn snapshot(context: &MockContext, swap_chain: &SwapChain<MockDevice>) -> Snapshot {
+    let data = swap_chain.lock();
+    let (attached, back_buffer) = match data.back_buffer {
+        BackBuffer::Attached => (true, context.surface.as_ref().map(|surface| surface.id)),
+        BackBuffer::Detached(ref surface) => (false, Some(surface.id)),
+        BackBuffer::TakenAttached => (true, None),
+        BackBuffer::TakenDetached => (false, None),
+    };
+    Snapshot {
+        attached,
+        back_buffer,
+        pending: data
+            .pending_surfaces
+            .iter()
+            .map(|frame| frame.surface.id)
+            .collect(),
+        recycled: data
+            .recycled_surfaces
+            .iter()
+            .map(|recycled| recycled.surface.id)
+            .collect(),
+        size: data.size,
+    }
+}
+
+const SIZE: Size2D<i32> = Size2D::new(64, 64);
+
+// A swap chain with a pending frame, and a recycled surface if `recycled` is true.
+fn swap_chain(
+    device: &mut MockDevice,
+    context: &mut MockContext,
+    attached: bool,
+    recycled: bool,
+) -> SwapChain<MockDevice> {
+    let options = SwapChainOptions {
+        queue_depth: 2,
+        pool_capacity: 2,
+        ..SwapChainOptions::default()
+    };
+    let access = SurfaceAccess::GPUOnly;
+    let swap_chain = if attached {
+        SwapChain::create_attached_with_options(device, context, access, options)
+    } else {
+        SwapChain::create_detached_with_options(device, context, access, SIZE, options)
+    }
+    .unwrap();
+    swap_chain
+        .swap_buffers(device, context, PreserveBuffer::No)
+        .unwrap();
+    if recycled {
+        swap_chain
+            .swap_buffers(device, context, PreserveBuffer::No)
+            .unwrap();
+        let surface = swap_chain.take_surface().unwrap();
+        swap_chain.recycle_surface(surface);
+    }
+    swap_chain
+}
+
+// Destroy everything, checking that no surface was leaked.
+fn tear_down(
+    device: &mut MockDevice,
+    mut context: MockContext,
+    swap_chains: &[&SwapChain<MockDevice>],
+) {
+    device.recover();
+    for swap_chain in swap_chains {
+        swap_chain.destroy(device, &mut context).unwrap();
+        assert!(swap_chain.lock().orphans.is_empty());
+    }
+    device.destroy_context(&mut context).unwrap();
+    assert_eq!(device.live_surfaces.get(), 0);
+}
+
+// Run `op` on a fresh swap chain with each failure injected in turn,
+// checking that it fails when expected, and leaves the swap chain as it was when it does.
+fn check_failures(
+    attached: bool,
+    recycled: bool,
+    expected: &[Call],
+    op: impl Fn(&mut MockDevice, &mut MockContext, &SwapChain<MockDevice>) -> Result<(), Error>,
+) {
+    for &call in &CALLS {
+        let mut device = MockDevice::default();
+        let mut context = device.context(SIZE);
+        let swap_chain = swap_chain(&mut device, &mut context, attached, recycled);
+        let before = snapshot(&context, &swap_chain);
+        device.fail(call);
+        let result = op(&mut device, &mut context, &swap_chain);
+        assert_eq!(
+            result.is_err(),
+            expected.contains(&call),
+            "{:?} (attached: {}, recycled: {}) returned {:?}",
+            call,
+            attached,
+            recycled,
+            result
+        );
+        if result.is_err() {
+            assert_eq!(before, snapshot(&context, &swap_chain), "{:?}", call);
+        }
+        tear_down(&mut device, context, &[&swap_chain]);
+    }
+}
+
+fn swap(
+    device: &mut MockDevice,
+    context: &mut MockContext,
+    swap_chain: &SwapChain<MockDevice>,
+) -> Result<(), Error> {
+    swap_chain
+        .swap_buffers(device, context, PreserveBuffer::No)
+        .map(drop)
+}
+
+fn resize(
+    device: &mut MockDevice,
+    context: &mut MockContext,
+    swap_chain: &SwapChain<MockDevice>,
+) -> Result<(), Error> {
+    swap_chain.resize(device, context, Size2D::new(32, 32))
+}
+
+#[test]
+fn swap_buffers_attached_failures() {
+    let expected = [Call::Create, Call::Bind, Call::Unbind];
+    check_failures(true, false, &expected, swap);
+    let expected = [Call::Bind, Call::Unbind];
+    check_failures(true, true, &expected, swap);
+}
+
+#[test]
+fn swap_buffers_detached_failures() {
+    check_failures(false, false, &[Call::Create], swap);
+    check_failures(false, true, &[], swap);
+}
+
+#[test]
+fn resize_attached_failures() {
+    let expected = [Call::Create, Call::Bind, Call::Unbind];
+    check_failures(true, false, &expected, resize);
+    check_failures(true, true, &expected, resize);
+}
+
+#[test]
+fn resize_detached_failures() {
+    check_failures(false, false, &[Call::Create], resize);
+    check_failures(false, true, &[Call::Create], resize);
+}
+
+#[test]
+fn take_attachment_from_failures() {
+    for &call in &CALLS {
+        let mut device = MockDevice::default();
+        let mut context = device.context(SIZE);
+        let attached = swap_chain(&mut device, &mut context, true, true);
+        let detached = swap_chain(&mut device, &mut context, false, true);
+        let before = (snapshot(&context, &attached), snapshot(&context, &detached));
+        device.fail(call);
+        let result = detached.take_attachment_from(&mut device, &mut context, &attached);
+        let expected = [Call::Bind, Call::Unbind];
+        assert_eq!(
+            result.is_err(),
+            expected.contains(&call),
+            "{:?} returned {:?}",
+            call,
+            result
+        );
+        if result.is_err() {
+            let after = (snapshot(&context, &attached), snapshot(&context, &detached));
+            assert_eq!(before, after, "{:?}", call);
+        }
+        tear_down(&mut device, context, &[&attached, &detached]);
+    }
+}
+
+// The GL state which the swap chain saves and restores around blits and clears.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct GlState {
+    draw_fbo: GLuint,
+    read_fbo: GLuint,
+    clear_color: [f32; 4],
+    clear_depth: f64,
+    clear_stencil: gl::GLint,
+    color_mask: [gl::GLboolean; 4],
+    depth_mask: gl::GLboolean,
+    stencil_mask: GLuint,
+    scissor_box: [gl::GLint; 4],
+    scissor_test: bool,
+    rasterizer_discard: bool,
+}
+
+// A blit or clear made through the mock GL bindings, with the state it was made in.
+#[derive(Debug)]
+enum GlCall {
+    // The state, the source and destination rectangles, and the buffers
+    Blit(GlState, [Box2D<i32>; 2], GLbitfield),
+    Clear(GlState, GLbitfield),
+    Invalidate(GlState, Vec<GLenum>),
+}
+
+// The framebuffers and renderbuffers created through the mock GL bindings.
+#[derive(Default)]
+struct GlObjects {
+    next_name: GLuint,
+    framebuffers: Vec<GLuint>,
+    // The internal format and size of each renderbuffer, once it has storage
+    renderbuffers: FnvHashMap<GLuint, Option<(GLenum, Size2D<i32>)>>,
+    bound_renderbuffer: GLuint,
+    syncs: Vec<usize>,
+}
+
+thread_local! {
+    static GL_STATE: RefCell<GlState> = RefCell::new(GlState::default());
+    static GL_CALLS: RefCell<Vec<GlCall>> = const { RefCell::new(Vec::new()) };
+    static GL_OBJECTS: RefCell<GlObjects> = RefCell::new(GlObjects::default());
+    // The major and minor version reported by the mock GL bindings
+    static GL_VERSION: Cell<[gl::GLint; 2]> = const { Cell::new([3, 0]) };
+}
+
+fn gl_state() -> GlState {
+    GL_STATE.with(|state| state.borrow().clone())
+}
+
+fn with_gl_state<T>(op: impl FnOnce(&mut GlState) -> T) -> T {
+    GL_STATE.with(|state| op(&mut state.borrow_mut()))
+}
+
+fn take_gl_calls() -> Vec<GlCall> {
+    GL_CALLS.with(|calls| calls.take())
+}
+
+fn with_gl_objects<T>(op: impl FnOnce(&mut GlObjects) -> T) -> T {
+    GL_OBJECTS.with(|objects| op(&mut objects.borrow_mut()))
+}
+
+extern "system" fn get_integer_v(name: GLenum, data: *mut gl::GLint) {
+    let values = with_gl_state(|state| match name {
+        gl::MAX_SAMPLES => vec![8],
+        gl::RENDERBUFFER_BINDING => {
+            vec![with_gl_objects(|objects| objects.bound_renderbuffer) as _]
+        }
+        gl::DRAW_FRAMEBUFFER_BINDING => vec![state.draw_fbo as gl::GLint],
+        gl::READ_FRAMEBUFFER_BINDING => vec![state.read_fbo as gl::GLint],
+        gl::STENCIL_CLEAR_VALUE => vec![state.clear_stencil],
+        gl::STENCIL_WRITEMASK => vec![state.stencil_mask as gl::GLint],
+        gl::SCISSOR_BOX => state.scissor_box.to_vec(),
+        gl::MAJOR_VERSION => vec![GL_VERSION.with(Cell::get)[0]],
+        gl::MINOR_VERSION => vec![GL_VERSION.with(Cell::get)[1]],
+        _ => panic!("Unexpected glGetIntegerv({:#x})", name),
+    });
+    unsafe { ptr::copy_nonoverlapping(values.as_ptr(), data, values.len()) }
+}
+
+extern "system" fn get_float_v(name: GLenum, data: *mut gl::GLfloat) {
+    let values = with_gl_state(|state| match name {
+        gl::COLOR_CLEAR_VALUE => state.clear_color.to_vec(),
+        gl::DEPTH_CLEAR_VALUE => vec![state.clear_depth as gl::GLfloat],
+        _ => panic!("Unexpected glGetFloatv({:#x})", name),
+    });
+    unsafe { ptr::copy_nonoverlapping(values.as_ptr(), data, values.len()) }
+}
+
+extern "system" fn get_boolean_v(name: GLenum, data: *mut gl::GLboolean) {
+    let values = with_gl_state(|state| match name {
+        gl::COLOR_WRITEMASK => state.color_mask.to_vec(),
+        gl::DEPTH_WRITEMASK => vec![state.depth_mask],
+        _ => panic!("Unexpected glGetBooleanv({:#x})", name),
+    });
+    unsafe { ptr::copy_nonoverlapping(values.as_ptr(), data, values.len()) }
+}
+
+fn capability(state: &mut GlState, capability: GLenum) -> &mut bool {
+    match capability {
+        gl::SCISSOR_TEST => &mut state.scissor_test,
+        gl::RASTERIZER_DISCARD => &mut state.rasterizer_discard,
+        _ => panic!("Unexpected capability {:#x}", capability),
+    }
+}
+
+extern "system" fn is_enabled(cap: GLenum) -> gl::GLboolean {
+    with_gl_state(|state| *capability(state, cap) as gl::GLboolean)
+}
+
+extern "system" fn enable(cap: GLenum) {
+    with_gl_state(|state| *capability(state, cap) = true)
+}
+
+extern "system" fn disable(cap: GLenum) {
+    with_gl_state(|state| *capability(state, cap) = false)
+}
+
+extern "system" fn bind_framebuffer(target: GLenum, framebuffer: GLuint) {
+    with_gl_state(|state| match target {
+        gl::FRAMEBUFFER => {
+            state.draw_fbo = framebuffer;
+            state.read_fbo = framebuffer;
+        }
+        gl::DRAW_FRAMEBUFFER => state.draw_fbo = framebuffer,
+        gl::READ_FRAMEBUFFER => state.read_fbo = framebuffer,
+        _ => panic!("Unexpected framebuffer target {:#x}", target),
+    })
+}
+
+extern "system" fn clear_color(r: gl::GLfloat, g: gl::GLfloat, b: gl::GLfloat, a: gl::GLfloat) {
+    with_gl_state(|state| state.clear_color = [r, g, b, a])
+}
+
+extern "system" fn clear_depth(depth: gl::GLdouble) {
+    with_gl_state(|state| state.clear_depth = depth)
+}
+
+extern "system" fn clear_stencil(stencil: gl::GLint) {
+    with_gl_state(|state| state.clear_stencil = stencil)
+}
+
+extern "system" fn color_mask(
+    r: gl::GLboolean,
+    g: gl::GLboolean,
+    b: gl::GLboolean,
+    a: gl::GLboolean,
+) {
+    with_gl_state(|state| state.color_mask = [r, g, b, a])
+}
+
+extern "system" fn depth_mask(mask: gl::GLboolean) {
+    with_gl_state(|state| state.depth_mask = mask)
+}
+
+extern "system" fn stencil_mask(mask: GLuint) {
+    with_gl_state(|state| state.stencil_mask = mask)
+}
+
+extern "system" fn scissor(x: gl::GLint, y: gl::GLint, width: GLsizei, height: GLsizei) {
+    with_gl_state(|state| state.scissor_box = [x, y, width, height])
+}
+
+extern "system" fn clear(mask: GLbitfield) {
+    let call = GlCall::Clear(gl_state(), mask);
+    GL_CALLS.with(|calls| calls.borrow_mut().push(call));
+}
+
+#[allow(clippy::too_many_arguments)]
+extern "system" fn blit_framebuffer(
+    src_x0: gl::GLint,
+    src_y0: gl::GLint,
+    src_x1: gl::GLint,
+    src_y1: gl::GLint,
+    dst_x0: gl::GLint,
+    dst_y0: gl::GLint,
+    dst_x1: gl::GLint,
+    dst_y1: gl::GLint,
+    mask: GLbitfield,
+    _: GLenum,
+) {
+    let rects = [
+        rect(src_x0, src_y0, src_x1, src_y1),
+        rect(dst_x0, dst_y0, dst_x1, dst_y1),
+    ];
+    let call = GlCall::Blit(gl_state(), rects, mask);
+    GL_CALLS.with(|calls| calls.borrow_mut().push(call));
+}
+
+extern "system" fn invalidate_framebuffer(
+    _: GLenum,
+    num_attachments: GLsizei,
+    attachments: *const GLenum,
+) {
+    let attachments = unsafe { std::slice::from_raw_parts(attachments, num_attachments as usize) };
+    let call = GlCall::Invalidate(gl_state(), attachments.to_vec());
+    GL_CALLS.with(|calls| calls.borrow_mut().push(call));
+}
+
+extern "system" fn get_error() -> GLenum {
+    gl::NO_ERROR
+}
+
+fn gen_names(n: GLsizei, names: *mut GLuint) -> Vec<GLuint> {
+    let generated: Vec<GLuint> = with_gl_objects(|objects| {
+        (0..n)
+            .map(|_| {
+                objects.next_name += 1;
+                1000 + objects.next_name
+            })
+            .collect()
+    });
+    unsafe { ptr::copy_nonoverlapping(generated.as_ptr(), names, generated.len()) }
+    generated
+}
+
+extern "system" fn gen_framebuffers(n: GLsizei, names: *mut GLuint) {
+    let names = gen_names(n, names);
+    with_gl_objects(|objects| objects.framebuffers.extend(names))
+}
+
+extern "system" fn gen_renderbuffers(n: GLsizei, names: *mut GLuint) {
+    let names = gen_names(n, names);
+    with_gl_objects(|objects| {
+        objects
+            .renderbuffers
+            .extend(names.into_iter().map(|name| (name, None)))
+    })
+}
+
+extern "system" fn delete_framebuffers(n: GLsizei, names: *const GLuint) {
+    let names = unsafe { std::slice::from_raw_parts(names, n as usize) };
+    with_gl_objects(|objects| objects.framebuffers.retain(|name| !names.contains(name)))
+}
+
+extern "system" fn delete_renderbuffers(n: GLsizei, names: *const GLuint) {
+    let names = unsafe { std::slice::from_raw_parts(names, n as usize) };
+    with_gl_objects(|objects| {
+        objects
+            .renderbuffers
+            .retain(|name, _| !names.contains(name))
+    })
+}
+
+extern "system" fn bind_renderbuffer(_: GLenum, renderbuffer: GLuint) {
+    with_gl_objects(|objects| objects.bound_renderbuffer = renderbuffer)
+}
+
+extern "system" fn renderbuffer_storage_multisample(
+    _: GLenum,
+    _: GLsizei,
+    format: GLenum,
+    width: GLsizei,
+    height: GLsizei,
+) {
+    with_gl_objects(|objects| {
+        let name = objects.bound_renderbuffer;
+        objects
+            .renderbuffers
+            .insert(name, Some((format, Size2D::new(width, height))));
+    })
+}
+
+extern "system" fn framebuffer_renderbuffer(_: GLenum, _: GLenum, _: GLenum, _: GLuint) {}
+
+extern "system" fn check_framebuffer_status(_: GLenum) -> GLenum {
+    gl::FRAMEBUFFER_COMPLETE
+}
+
+extern "system" fn fence_sync(_: GLenum, _: GLbitfield) -> GLsync {
+    with_gl_objects(|objects| {
+        objects.next_name += 1;
+        objects.syncs.push(objects.next_name as usize);
+        objects.next_name as usize as GLsync
+    })
+}
+
+extern "system" fn delete_sync(sync: GLsync) {
+    with_gl_objects(|objects| objects.syncs.retain(|&name| name != sync as usize))
+}
+
+extern "system" fn flush() {}
+
+// GL bindings which track the state and objects used by the swap chain, and record its blits and clears.
+fn mock_gl() -> Gl {
+    Gl::Gl(gl::ffi_gl::Gl::load_with(|name| match name {
+        "glGetIntegerv" => get_integer_v as *const c_void,
+        "glGetFloatv" => get_float_v as *const c_void,
+        "glGetBooleanv" => get_boolean_v as *const c_void,
+        "glIsEnabled" => is_enabled as *const c_void,
+        "glEnable" => enable as *const c_void,
+        "glDisable" => disable as *const c_void,
+        "glBindFramebuffer" => bind_framebuffer as *const c_void,
+        "glClearColor" => clear_color as *const c_void,
+        "glClearDepth" => clear_depth as *const c_void,
+        "glClearStencil" => clear_stencil as *const c_void,
+        "glColorMask" => color_mask as *const c_void,
+        "glDepthMask" => depth_mask as *const c_void,
+        "glStencilMask" => stencil_mask as *const c_void,
+        "glScissor" => scissor as *const c_void,
+        "glClear" => clear as *const c_void,
+        "glBlitFramebuffer" => blit_framebuffer as *const c_void,
+        "glInvalidateFramebuffer" => invalidate_framebuffer as *const c_void,
+        "glGetError" => get_error as *const c_void,
+        "glGenFramebuffers" => gen_framebuffers as *const c_void,
+        "glGenRenderbuffers" => gen_renderbuffers as *const c_void,
+        "glDeleteFramebuffers" => delete_framebuffers as *const c_void,
+        "glDeleteRenderbuffers" => delete_renderbuffers as *const c_void,
+        "glBindRenderbuffer" => bind_renderbuffer as *const c_void,
+        "glRenderbufferStorageMultisample" => renderbuffer_storage_multisample as *const c_void,
+        "glFramebufferRenderbuffer" => framebuffer_renderbuffer as *const c_void,
+        "glCheckFramebufferStatus" => check_framebuffer_status as *const c_void,
+        "glFenceSync" => fence_sync as *const c_void,
+        "glDeleteSync" => delete_sync as *const c_void,
+        "glFlush" => flush as *const c_void,
+        _ => ptr::null(),
+    }))
+}
+
+#[test]
+fn blits_and_clears_restore_gl_state() {
+    for &scissor_test in &[false, true] {
+        let mut device = MockDevice::default();
+        let mut context = device.context(SIZE);
+        let swap_chain = swap_chain(&mut device, &mut context, true, false);
+        let gl = mock_gl();
+
+        let saved = GlState {
+            draw_fbo: 101,
+            read_fbo: 102,
+            clear_color: [0.25, 0.5, 0.75, 1.],
+            clear_depth: 0.5,
+            clear_stencil: 3,
+            color_mask: [1, 0, 1, 0],
+            depth_mask: 0,
+            stencil_mask: 0xF0,
+            scissor_box: [1, 2, 3, 4],
+            scissor_test,
+            rasterizer_discard: true,
+        };
+        with_gl_state(|state| *state = saved.clone());
+        take_gl_calls();
+
+        // Swapping preserves the back buffer with a blit
+        swap_chain
+            .swap_buffers(&mut device, &mut context, PreserveBuffer::Yes(&gl))
+            .unwrap();
+        assert_eq!(gl_state(), saved);
+        match &take_gl_calls()[..] {
+            [GlCall::Blit(state, _, mask)] => {
+                let all_buffers =
+                    gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT;
+                assert_eq!(*mask, all_buffers);
+                assert_ne!(state.read_fbo, saved.read_fbo);
+                assert_ne!(state.draw_fbo, saved.draw_fbo);
+                assert!(!state.scissor_test);
+                assert!(!state.rasterizer_discard);
+            }
+            calls => panic!("Expected a blit, got {:?}", calls),
+        }
+
+        // Clearing part of the back buffer enables scissoring while it clears
+        let options = ClearOptions {
+            color: Some([0., 1., 0., 1.]),
+            depth: None,
+            stencil: None,
+            scissor: Some(Box2D::new(Point2D::new(8, 8), Point2D::new(24, 16))),
+        };
+        swap_chain
+            .clear_surface_with(&mut device, &mut context, &gl, options)
+            .unwrap();
+        assert_eq!(gl_state(), saved);
+        let back_buffer = context.surface.as_ref().unwrap().id as GLuint;
+        match &take_gl_calls()[..] {
+            [GlCall::Clear(state, mask)] => {
+                assert_eq!(*mask, gl::COLOR_BUFFER_BIT);
+                assert_eq!(state.draw_fbo, back_buffer);
+                assert_eq!(state.clear_color, [0., 1., 0., 1.]);
+                assert_eq!(state.color_mask, [1, 1, 1, 1]);
+                assert_eq!(state.scissor_box, [8, 8, 16, 8]);
+                assert!(state.scissor_test);
+                assert!(!state.rasterizer_discard);
+            }
+            calls => panic!("Expected a clear, got {:?}", calls),
+        }
+
+        tear_down(&mut device, context, &[&swap_chain]);
+    }
+}
+
+#[test]
+fn multisampled_framebuffers_follow_resize_and_destroy() {
+    let mut device = MockDevice::default();
+    let mut context = device.context(SIZE);
+    let swap_chains: SwapChains<usize, MockDevice> = SwapChains::new();
+    let access = SurfaceAccess::GPUOnly;
+    let options = SwapChainOptions {
+        samples: 4,
+        ..SwapChainOptions::default()
+    };
+    swap_chains
+        .create_attached_swap_chain_with_options(1, &mut device, &mut context, access, options)
+        .unwrap();
+    swap_chains
+        .create_detached_swap_chain(2, SIZE, &mut device, &mut context, access)
+        .unwrap();
+    let multisampled = swap_chains.get(1).unwrap();
+    let other = swap_chains.get(2).unwrap();
+    let gl = mock_gl();
+    let renderbuffers = || {
+        let mut renderbuffers: Vec<_> = with_gl_objects(|objects| {
+            objects
+                .renderbuffers
+                .values()
+                .map(|storage| storage.unwrap())
+                .collect()
+        });
+        renderbuffers.sort_by_key(|&(format, _)| format);
+        renderbuffers
+    };
+
+    // The color buffer is always RGBA, to match the surfaces
+    let framebuffer = multisampled
+        .multisampled_framebuffer(&mut device, &mut context, &gl)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        renderbuffers(),
+        [(gl::RGBA8, SIZE), (gl::DEPTH24_STENCIL8, SIZE)]
+    );
+
+    // Swapping after a resize resolves the stale framebuffer, then resizes it
+    let size = Size2D::new(32, 32);
+    multisampled
+        .resize(&mut device, &mut context, size)
+        .unwrap();
+    take_gl_calls();
+    let options = SwapOptions {
+        preserve_buffer: PreserveBuffer::No,
+        metadata: (),
+        damage: None,
+        gl: Some(&gl),
+    };
+    multisampled
+        .swap_buffers_with(&mut device, &mut context, options)
+        .unwrap();
+    match &take_gl_calls()[..] {
+        [GlCall::Blit(state, _, gl::COLOR_BUFFER_BIT)] => assert_eq!(state.read_fbo, framebuffer),
+        calls => panic!("Expected a resolve, got {:?}", calls),
+    }
+    assert_eq!(
+        renderbuffers(),
+        [(gl::RGBA8, size), (gl::DEPTH24_STENCIL8, size)]
+    );
+
+    // Destroying the swap chain leaves the framebuffer to the next call with GL bindings
+    drop(multisampled);
+    swap_chains.destroy(1, &mut device, &mut context).unwrap();
+    assert_eq!(
+        with_gl_objects(|objects| objects.framebuffers.clone()),
+        [framebuffer]
+    );
+    other
+        .clear_surface_with(&mut device, &mut context, &gl, ClearOptions::default())
+        .unwrap();
+    assert!(with_gl_objects(|objects| objects.framebuffers.is_empty()));
+    assert!(renderbuffers().is_empty());
+
+    drop(other);
+    swap_chains.destroy_all(&mut device, &mut context).unwrap();
+    device.destroy_context(&mut context).unwrap();
+    assert_eq!(device.live_surfaces.get(), 0);
+}
+
+#[test]
+fn fenced_consumers_get_the_fence() {
+    let mut device = MockDevice::default();
+    let mut context = device.context(SIZE);
+    let options = SwapChainOptions {
+        fence_sync: true,
+        ..SwapChainOptions::default()
+    };
+    let access = SurfaceAccess::GPUOnly;
+    let swap_chain =
+        SwapChain::create_attached_with_options(&mut device, &mut context, access, options)
+            .unwrap();
+    let gl = mock_gl();
+    let swap = |device: &mut MockDevice, context: &mut MockContext| {
+        let options = SwapOptions {
+            preserve_buffer: PreserveBuffer::No,
+            metadata: (),
+            damage: None,
+            gl: Some(&gl),
+        };
+        swap_chain
+            .swap_buffers_with(device, context, options)
+            .unwrap()
+    };
+    let live_syncs = || with_gl_objects(|objects| objects.syncs.len());
+
+    // The fenced ways of taking a frame hand over the fence
+    let id = swap(&mut device, &mut context);
+    let (frame, fence) = swap_chain
+        .take_frame_with_fence_timeout(Duration::from_secs(1))
+        .unwrap();
+    assert_eq!(frame.id, id);
+    fence.unwrap().delete(&gl);
+    swap_chain.recycle_surface(frame.surface);
+    let id = swap(&mut device, &mut context);
+    let (frame, fence) = swap_chain.wait_for_frame_with_fence().unwrap();
+    assert_eq!(frame.id, id);
+    fence.unwrap().delete(&gl);
+    swap_chain.recycle_surface(frame.surface);
+    #[cfg(feature = "async")]
+    {
+        let id = swap(&mut device, &mut context);
+        let waker = Waker::from(Arc::new(Woken(AtomicBool::new(false))));
+        let mut cx = task::Context::from_waker(&waker);
+        match Pin::new(&mut swap_chain.next_frame_with_fence()).poll(&mut cx) {
+            Poll::Ready(Some((frame, Some(fence)))) => {
+                assert_eq!(frame.id, id);
+                fence.delete(&gl);
+                swap_chain.recycle_surface(frame.surface);
+            }
+            poll => panic!("Expected a fenced frame, got {:?}", poll.map(|_| ())),
+        }
+    }
+    assert_eq!(live_syncs(), 0);
+
+    // The other ways discard it, leaving it for the producer to delete
+    swap(&mut device, &mut context);
+    let surface = swap_chain
+        .take_surface_timeout(Duration::from_secs(1))
+        .unwrap();
+    assert_eq!(live_syncs(), 1);
+    swap_chain.recycle_surface(surface);
+    // Swapping deletes the discarded fence, and fences the new front buffer
+    swap(&mut device, &mut context);
+    assert_eq!(live_syncs(), 1);
+
+    tear_down(&mut device, context, &[&swap_chain]);
+}
+
+#[test]
+fn errors_carry_the_swap_chain_id() {
+    let mut device = MockDevice::default();
+    let mut context = device.context(SIZE);
+    let swap_chains: SwapChains<usize, MockDevice> = SwapChains::new();
+    let access = SurfaceAccess::GPUOnly;
+    swap_chains
+        .create_attached_swap_chain(1, &mut device, &mut context, access)
+        .unwrap();
+    let swap_chain = swap_chains.get(1).unwrap();
+
+    let err = swap_chain
+        .resize(&mut device, &mut context, Size2D::new(0, 0))
+        .unwrap_err();
+    assert_eq!(err.swap_chain_id.as_deref(), Some("1"));
+    assert_eq!(err.context_id, Some(context.id));
+
+    swap_chains.destroy(1, &mut device, &mut context).unwrap();
+    let err = swap_chain
+        .swap_buffers(&mut device, &mut context, PreserveBuffer::No)
+        .unwrap_err();
+    assert!(matches!(err.kind, ErrorKind::Destroyed));
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "swap chain destroyed for swap chain 1 for context {:?}",
+            context.id
+        )
+    );
+
+    // Swap chains outside a collection have no id
+    let detached = SwapChain::create_detached(&mut device, &mut context, access, SIZE).unwrap();
+    let err = detached
+        .resize(&mut device, &mut context, Size2D::new(0, 0))
+        .unwrap_err();
+    assert_eq!(err.swap_chain_id, None);
+
+    drop(swap_chain);
+    tear_down(&mut device, context, &[&detached]);
+}
+
+// An attached swap chain with the given present mode and queue depth.
+fn queued_swap_chain(
+    device: &mut MockDevice,
+    context: &mut MockContext,
+    present_mode: PresentMode,
+    queue_depth: usize,
+) -> SwapChain<MockDevice> {
+    let options = SwapChainOptions {
+        queue_depth,
+        present_mode,
+        ..SwapChainOptions::default()
+    };
+    let access = SurfaceAccess::GPUOnly;
+    SwapChain::create_attached_with_options(device, context, access, options).unwrap()
+}
+
+#[test]
+fn fifo_queue_keeps_every_frame_in_order() {
+    for queue_depth in 1..=4 {
+        let mut device = MockDevice::default();
+        let mut context = device.context(SIZE);
+        let swap_chain =
+            queued_swap_chain(&mut device, &mut context, PresentMode::Fifo, queue_depth);
+
+        let swapped: Vec<FrameId> = (0..queue_depth)
+            .map(|_| {
+                swap_chain
+                    .swap_buffers(&mut device, &mut context, PreserveBuffer::No)
+                    .unwrap()
+            })
+            .collect();
+        let before = snapshot(&context, &swap_chain);
+        let err = swap_chain
+            .swap_buffers(&mut device, &mut context, PreserveBuffer::No)
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::QueueFull));
+        assert_eq!(before, snapshot(&context, &swap_chain));
+
+        let mut taken = Vec::new();
+        while let Some(frame) = swap_chain.take_frame() {
+            taken.push(frame.id);
+            swap_chain.recycle_surface(frame.surface);
+        }
+        assert_eq!(taken, swapped, "queue depth {}", queue_depth);
+        let frames = queue_depth as u64;
+        let stats = FrameStats {
+            presented: frames,
+            taken: frames,
+            dropped: 0,
+        };
+        assert_eq!(swap_chain.frame_stats(), stats);
+
+        tear_down(&mut device, context, &[&swap_chain]);
+    }
+}
+
+#[test]
+fn present_modes_drop_frames_when_the_queue_is_full() {
+    // Mailbox drops the oldest pending frame, and FIFO relaxed the newest
+    let modes = [
+        (PresentMode::Mailbox, [FrameId(1), FrameId(2)]),
+        (PresentMode::FifoRelaxed, [FrameId(0), FrameId(2)]),
+    ];
+    for (present_mode, expected) in modes {
+        let mut device = MockDevice::default();
+        let mut context = device.context(SIZE);
+        let swap_chain = queued_swap_chain(&mut device, &mut context, present_mode, 2);
+        for id in 0..3 {
+            let frame_id = swap_chain
+                .swap_buffers(&mut device, &mut context, PreserveBuffer::No)
+                .unwrap();
+            assert_eq!(frame_id, FrameId(id));
+        }
+        // The dropped surface is reused as the back buffer
+        assert_eq!(device.live_surfaces.get(), 3);
+
+        let mut taken = Vec::new();
+        while let Some(frame) = swap_chain.take_frame() {
+            taken.push(frame.id);
+            swap_chain.recycle_surface(frame.surface);
+        }
+        assert_eq!(taken, expected, "{:?}", present_mode);
+        let stats = FrameStats {
+            presented: 3,
+            taken: 2,
+            dropped: 1,
+        };
+        assert_eq!(swap_chain.frame_stats(), stats, "{:?}", present_mode);
+
+        tear_down(&mut device, context, &[&swap_chain]);
+    }
+}
+
+#[test]
+fn fifo_swap_waits_for_the_consumer() {
+    let mut device = MockDevice::default();
+    let mut context = device.context(SIZE);
+    let swap_chain = queued_swap_chain(&mut device, &mut context, PresentMode::Fifo, 1);
+    swap_chain
+        .swap_buffers(&mut device, &mut context, PreserveBuffer::No)
+        .unwrap();
+
+    // A full queue times out
+    let err = swap_chain
+        .swap_buffers_timeout(
+            &mut device,
+            &mut context,
+            SwapOptions::default(),
+            Duration::from_millis(10),
+        )
+        .unwrap_err();
+    assert!(matches!(err.kind, ErrorKind::QueueFull));
+    let stats = FrameStats {
+        presented: 1,
+        taken: 0,
+        dropped: 0,
+    };
+    assert_eq!(swap_chain.frame_stats(), stats);
+
+    // Taking a frame on another thread wakes the producer
+    let consumer = swap_chain.clone();
+    let taker = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        consumer.take_surface().unwrap()
+    });
+    let frame_id = swap_chain
+        .swap_buffers_timeout(
+            &mut device,
+            &mut context,
+            SwapOptions::default(),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+    assert_eq!(frame_id, FrameId(1));
+    swap_chain.recycle_surface(taker.join().unwrap());
+    let stats = FrameStats {
+        presented: 2,
+        taken: 1,
+        dropped: 0,
+    };
+    assert_eq!(swap_chain.frame_stats(), stats);
+
+    let surface = swap_chain.take_surface().unwrap();
+    swap_chain.recycle_surface(surface);
+    tear_down(&mut device, context, &[&swap_chain]);
+}
+
+#[test]
+fn waiting_consumers_wake_on_swap_and_destroy() {
+    let mut device = MockDevice::default();
+    let mut context = device.context(SIZE);
+    let swap_chain = queued_swap_chain(&mut device, &mut context, PresentMode::Fifo, 2);
+
+    // Waiting times out if the producer doesn't swap buffers
+    assert!(swap_chain
+        .take_surface_timeout(Duration::from_millis(10))
+        .is_none());
+
+    // Waiting consumers are woken when the producer swaps buffers
+    let consumer = swap_chain.clone();
+    let timeout_waiter =
+        thread::spawn(move || consumer.take_surface_timeout(Duration::from_secs(10)));
+    let consumer = swap_chain.clone();
+    let waiter = thread::spawn(move || consumer.wait_for_frame());
+    thread::sleep(Duration::from_millis(50));
+    for _ in 0..2 {
+        swap_chain
+            .swap_buffers(&mut device, &mut context, PreserveBuffer::No)
+            .unwrap();
+    }
+    swap_chain.recycle_surface(timeout_waiter.join().unwrap().unwrap());
+    swap_chain.recycle_surface(waiter.join().unwrap().unwrap());
+
+    // Waiting consumers are woken, and get `None`, when the producer destroys the swap chain
+    let consumer = swap_chain.clone();
+    let timeout_waiter =
+        thread::spawn(move || consumer.take_surface_timeout(Duration::from_secs(10)));
+    let consumer = swap_chain.clone();
+    let waiter = thread::spawn(move || consumer.wait_for_frame());
+    thread::sleep(Duration::from_millis(50));
+    swap_chain.destroy(&mut device, &mut context).unwrap();
+    assert!(timeout_waiter.join().unwrap().is_none());
+    assert!(waiter.join().unwrap().is_none());
+
+    tear_down(&mut device, context, &[&swap_chain]);
+}
+
+// Swap buffers `swaps` times, with a consumer which holds on to the last `held` frames,
+// and return the back buffer age after each swap.
+fn back_buffer_ages(
+    device: &mut MockDevice,
+    context: &mut MockContext,
+    swap_chain: &SwapChain<MockDevice>,
+    gl: Option<&Gl>,
+    swaps: usize,
+    held: usize,
+) -> Vec<u64> {
+    let mut taken = VecDeque::new();
+    let ages = (0..swaps)
+        .map(|_| {
+            let options = SwapOptions {
+                gl,
+                ..SwapOptions::default()
+            };
+            swap_chain
+                .swap_buffers_with(device, context, options)
+                .unwrap();
+            let age = swap_chain.back_buffer_age();
+            taken.push_back(swap_chain.take_surface().unwrap());
+            if taken.len() > held {
+                swap_chain.recycle_surface(taken.pop_front().unwrap());
+            }
+            age
+        })
+        .collect();
+    for surface in taken {
+        swap_chain.recycle_surface(surface);
+    }
+    ages
+}
+
+#[test]
+fn back_buffer_age_counts_the_frames_since_it_was_presented() {
+    // The first swaps need new back buffers, after which the back buffer was presented
+    // two frames ago with double buffering, and three with triple buffering
+    for (held, expected) in [(0, vec![0, 2, 2, 2]), (1, vec![0, 0, 3, 3, 3])] {
+        let mut device = MockDevice::default();
+        let mut context = device.context(SIZE);
+        let options = SwapChainOptions {
+            pool_capacity: 2,
+            ..SwapChainOptions::default()
+        };
+        let access = SurfaceAccess::GPUOnly;
+        let swap_chain =
+            SwapChain::create_attached_with_options(&mut device, &mut context, access, options)
+                .unwrap();
+        assert_eq!(swap_chain.back_buffer_age(), 0);
+        let ages = back_buffer_ages(
+            &mut device,
+            &mut context,
+            &swap_chain,
+            None,
+            expected.len(),
+            held,
+        );
+        assert_eq!(ages, expected, "holding {} frames", held);
+        tear_down(&mut device, context, &[&swap_chain]);
+    }
+}
+
+#[test]
+fn back_buffer_age_resets_when_the_back_buffer_is_new() {
+    let mut device = MockDevice::default();
+    let mut context = device.context(SIZE);
+    let options = SwapChainOptions {
+        pool_capacity: 1,
+        prewarmed_surfaces: 1,
+        ..SwapChainOptions::default()
+    };
+    let access = SurfaceAccess::GPUOnly;
+    let swap_chain =
+        SwapChain::create_detached_with_options(&mut device, &mut context, access, SIZE, options)
+            .unwrap();
+
+    // A prewarmed back buffer has never been presented
+    let ages = back_buffer_ages(&mut device, &mut context, &swap_chain, None, 3, 0);
+    assert_eq!(ages, [0, 2, 2]);
+
+    // Resizing replaces the back buffer
+    swap_chain
+        .resize(&mut device, &mut context, Size2D::new(32, 32))
+        .unwrap();
+    assert_eq!(swap_chain.back_buffer_age(), 0);
+    let ages = back_buffer_ages(&mut device, &mut context, &swap_chain, None, 3, 0);
+    assert_eq!(ages, [0, 2, 2]);
+
+    // Repairing replaces a back buffer which was taken and never recycled
+    let surface_texture = swap_chain
+        .take_surface_texture(&device, &mut context)
+        .unwrap();
+    let report = swap_chain.repair(&mut device, &mut context).unwrap();
+    assert!(report.back_buffer_replaced);
+    assert_eq!(swap_chain.back_buffer_age(), 0);
+    let mut surface = device
+        .destroy_surface_texture(&mut context, surface_texture)
+        .unwrap();
+    device.destroy_surface(&mut context, &mut surface).unwrap();
+
+    tear_down(&mut device, context, &[&swap_chain]);
+}
+
+#[test]
+fn back_buffer_age_is_zero_when_the_back_buffer_is_invalidated() {
+    let gl = mock_gl();
+    GL_VERSION.with(|version| version.set([4, 3]));
+    let mut device = MockDevice::default();
+    let mut context = device.context(SIZE);
+    let options = SwapChainOptions {
+        pool_capacity: 1,
+        invalidate_back_buffer: true,
+        ..SwapChainOptions::default()
+    };
+    let access = SurfaceAccess::GPUOnly;
+    let swap_chain =
+        SwapChain::create_attached_with_options(&mut device, &mut context, access, options)
+            .unwrap();
+
+    let ages = back_buffer_ages(&mut device, &mut context, &swap_chain, Some(&gl), 3, 0);
+    assert_eq!(ages, [0, 0, 0]);
+    // Each swap invalidates every attachment of the new back buffer
+    let back_buffer = context.surface.as_ref().unwrap().id as GLuint;
+    let calls = take_gl_calls();
+    assert_eq!(calls.len(), 3);
+    match &calls[..] {
+        [.., GlCall::Invalidate(state, attachments)] => {
+            assert_eq!(state.draw_fbo, back_buffer);
+            let expected = [
+                gl::COLOR_ATTACHMENT0,
+                gl::DEPTH_ATTACHMENT,
+                gl::STENCIL_ATTACHMENT,
+            ];
+            assert_eq!(attachments[..], expected);
+        }
+        calls => panic!("Expected an invalidation, got {:?}", calls),
+    }
+
+    tear_down(&mut device, context, &[&swap_chain]);
+}
+
+fn rect(x0: i32, y0: i32, x1: i32, y1: i32) -> Box2D<i32> {
+    Box2D::new(Point2D::new(x0, y0), Point2D::new(x1, y1))
+}
+
+// Swap buffers, preserving the back buffer, with a consumer which holds on to the last `held` frames.
+// Returns the region which was blitted to preserve the back buffer.
+fn preserving_swap(
+    device: &mut MockDevice,
+    context: &mut MockContext,
+    swap_chain: &SwapChain<MockDevice>,
+    gl: &Gl,
+    damage: Option<Vec<Box2D<i32>>>,
+    taken: &mut VecDeque<MockSurface>,
+    held: usize,
+) -> Box2D<i32> {
+    let options = SwapOptions {
+        preserve_buffer: PreserveBuffer::Yes(gl),
+        damage,
+        gl: Some(gl),
+        ..SwapOptions::default()
+    };
+    swap_chain
+        .swap_buffers_with(device, context, options)
+        .unwrap();
+    taken.push_back(swap_chain.take_surface().unwrap());
+    if taken.len() > held {
+        swap_chain.recycle_surface(taken.pop_front().unwrap());
+    }
+    match &take_gl_calls()[..] {
+        [GlCall::Blit(_, [src, dst], _)] => {
+            assert_eq!(src, dst);
+            *src
+        }
+        calls => panic!("Expected a blit, got {:?}", calls),
+    }
+}
+
+#[test]
+fn preserving_blits_the_stale_region() {
+    let gl = mock_gl();
+    let mut device = MockDevice::default();
+    let mut context = device.context(SIZE);
+    let options = SwapChainOptions {
+        pool_capacity: 2,
+        ..SwapChainOptions::default()
+    };
+    let access = SurfaceAccess::GPUOnly;
+    let swap_chain =
+        SwapChain::create_attached_with_options(&mut device, &mut context, access, options)
+            .unwrap();
+    let full = Box2D::from_size(SIZE);
+    let mut taken = VecDeque::new();
+    let mut swap = |taken: &mut VecDeque<MockSurface>, damage, held| {
+        preserving_swap(
+            &mut device,
+            &mut context,
+            &swap_chain,
+            &gl,
+            damage,
+            taken,
+            held,
+        )
+    };
+
+    // New back buffers are blitted in full
+    let damage = Some(vec![rect(0, 0, 8, 8)]);
+    assert_eq!(swap(&mut taken, damage.clone(), 1), full);
+    assert_eq!(swap(&mut taken, damage, 1), full);
+
+    // With triple buffering, the back buffer misses the damage from two frames
+    let damage = Some(vec![rect(8, 8, 16, 16), rect(0, 0, 4, 4)]);
+    assert_eq!(swap(&mut taken, damage, 1), rect(0, 0, 16, 16));
+    let damage = Some(vec![rect(32, 32, 48, 48)]);
+    assert_eq!(swap(&mut taken, damage, 1), rect(0, 0, 48, 48));
+
+    // Damage outside the surface is clipped
+    let damage = Some(vec![rect(56, 56, 80, 80)]);
+    assert_eq!(swap(&mut taken, damage, 1), rect(32, 32, 64, 64));
+
+    // Unknown damage is treated as damage everywhere
+    assert_eq!(swap(&mut taken, None, 1), full);
+    let damage = Some(vec![rect(0, 0, 8, 8)]);
+    assert_eq!(swap(&mut taken, damage, 1), full);
+
+    // The back buffer is blitted in full if it was presented before the oldest frame in the history
+    let damage = Some(vec![rect(0, 0, 8, 8)]);
+    let old = taken.pop_front().unwrap();
+    for _ in 0..MAX_DAMAGE_HISTORY {
+        assert_eq!(swap(&mut taken, damage.clone(), 0), rect(0, 0, 8, 8));
+    }
+    // Hold on to the latest frame, so the old surface is the only one to recycle
+    assert_eq!(swap(&mut taken, damage.clone(), 1), rect(0, 0, 8, 8));
+    swap_chain.recycle_surface(old);
+    assert_eq!(swap(&mut taken, damage, 1), full);
+    for surface in taken {
+        swap_chain.recycle_surface(surface);
+    }
+
+    tear_down(&mut device, context, &[&swap_chain]);
+}
+
+#[test]
+fn frame_damage_covers_dropped_frames() {
+    let damage = [rect(0, 0, 8, 8), rect(8, 8, 16, 16), rect(16, 16, 24, 24)];
+    // Mailbox carries the damage from a dropped frame over to the next pending frame,
+    // and FIFO relaxed to the new frame which replaces it
+    let modes = [
+        (
+            PresentMode::Mailbox,
+            [
+                (FrameId(2), vec![damage[0], damage[1]]),
+                (FrameId(3), vec![damage[2]]),
+            ],
+        ),
+        (
+            PresentMode::FifoRelaxed,
+            [
+                (FrameId(1), vec![damage[0]]),
+                (FrameId(3), vec![damage[1], damage[2]]),
+            ],
+        ),
+    ];
+    for (present_mode, expected) in modes {
+        let mut device = MockDevice::default();
+        let mut context = device.context(SIZE);
+        let swap_chain = queued_swap_chain(&mut device, &mut context, present_mode, 2);
+        let swap = |device: &mut MockDevice, context: &mut MockContext, damage| {
+            let options = SwapOptions {
+                damage: Some(vec![damage]),
+                ..SwapOptions::default()
+            };
+            swap_chain
+                .swap_buffers_with(device, context, options)
+                .unwrap();
+        };
+        let take = || {
+            let frame = swap_chain.take_frame().unwrap();
+            swap_chain.recycle_surface(frame.surface);
+            (frame.id, frame.damage)
+        };
+
+        // The first frame is damaged everywhere
+        swap(&mut device, &mut context, damage[0]);
+        assert_eq!(take(), (FrameId(0), vec![Box2D::from_size(SIZE)]));
+
+        for &damage in &damage {
+            swap(&mut device, &mut context, damage);
+        }
+        assert_eq!([take(), take()], expected, "{:?}", present_mode);
+
+        // The first frame after a resize is damaged everywhere
+        let size = Size2D::new(32, 32);
+        swap_chain.resize(&mut device, &mut context, size).unwrap();
+        swap(&mut device, &mut context, damage[0]);
+        assert_eq!(take(), (FrameId(4), vec![Box2D::from_size(size)]));
+
+        tear_down(&mut device, context, &[&swap_chain]);
+    }
+}
+
+#[test]
+fn repair_replaces_a_missing_back_buffer() {
+    // Whether the swap chain is attached, and whether its back buffer is lost by leaking
+    // a surface texture rather than by destroying the context's surface
+    for (attached, leaked) in [(true, true), (false, true), (true, false)] {
+        let mut device = MockDevice::default();
+        let mut context = device.context(SIZE);
+        let swap_chain = swap_chain(&mut device, &mut context, attached, false);
+
+        // A swap chain which has its back buffer is left alone
+        let live_surfaces = device.live_surfaces.get();
+        let report = swap_chain.repair(&mut device, &mut context).unwrap();
+        assert!(!report.back_buffer_replaced);
+        assert_eq!(report.attached, attached);
+        assert_eq!(device.live_surfaces.get(), live_surfaces);
+
+        let mut surface = if leaked {
+            let surface_texture = swap_chain
+                .take_surface_texture(&device, &mut context)
+                .unwrap();
+            device
+                .destroy_surface_texture(&mut context, surface_texture)
+                .unwrap()
+        } else {
+            context.surface.take().unwrap()
+        };
+        device.destroy_surface(&mut context, &mut surface).unwrap();
+        assert!(swap(&mut device, &mut context, &swap_chain).is_err());
+
+        // Repairing fails, leaving the swap chain as it was, if the new back buffer can't be created
+        let before = snapshot(&context, &swap_chain);
+        device.fail(Call::Create);
+        assert!(swap_chain.repair(&mut device, &mut context).is_err());
+        assert_eq!(before, snapshot(&context, &swap_chain));
+        device.recover();
+
+        let report = swap_chain.repair(&mut device, &mut context).unwrap();
+        assert!(
+            report.back_buffer_replaced,
+            "attached: {}, leaked: {}",
+            attached, leaked
+        );
+        assert_eq!(report.attached, attached);
+        assert!(context.surface.is_some());
+        assert_eq!(device.live_surfaces.get(), live_surfaces);
+        swap(&mut device, &mut context, &swap_chain).unwrap();
+
+        tear_down(&mut device, context, &[&swap_chain]);
+    }
+}
+
+#[test]
+fn surfaces_recycled_into_destroyed_swap_chains_are_collected() {
+    let mut device = MockDevice::default();
+    let mut context = device.context(SIZE);
+    let swap_chains: SwapChains<usize, MockDevice> = SwapChains::new();
+    let access = SurfaceAccess::GPUOnly;
+    swap_chains
+        .create_attached_swap_chain(1, &mut device, &mut context, access)
+        .unwrap();
+    let swap_chain = swap_chains.get(1).unwrap();
+    swap(&mut device, &mut context, &swap_chain).unwrap();
+    let surface = swap_chain.take_surface().unwrap();
+
+    // The consumer still holds the swap chain, so it is kept when destroyed
+    swap_chains.destroy(1, &mut device, &mut context).unwrap();
+    assert!(swap_chain.is_destroyed());
+    let live_surfaces = device.live_surfaces.get();
+    swap_chain.recycle_surface(surface);
+    assert_eq!(swap_chain.lock().orphans.len(), 1);
+    assert_eq!(device.live_surfaces.get(), live_surfaces);
+
+    // The next call from the producer destroys the orphaned surface
+    assert_eq!(swap_chains.iter(&mut device, &mut context).count(), 0);
+    assert!(swap_chain.lock().orphans.is_empty());
+    assert_eq!(device.live_surfaces.get(), live_surfaces - 1);
+
+    // The swap chain is forgotten once the consumer drops it
+    drop(swap_chain);
+    swap_chains
+        .collect_garbage(&mut device, &mut context)
+        .unwrap();
+    assert!(swap_chains.destroyed().is_empty());
+
+    device.destroy_context(&mut context).unwrap();
+    assert_eq!(device.live_surfaces.get(), 0);
+}
+
+// A waker which records that it was woken.
+#[cfg(feature = "async")]
+struct Woken(AtomicBool);
+
+#[cfg(feature = "async")]
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn next_frame_wakes_on_swap_and_destroy() {
+    let mut device = MockDevice::default();
+    let mut context = device.context(SIZE);
+    let access = SurfaceAccess::GPUOnly;
+    let swap_chain = SwapChain::create_attached(&mut device, &mut context, access).unwrap();
+    let woken = Arc::new(Woken(AtomicBool::new(false)));
+    let waker = Waker::from(woken.clone());
+    let mut cx = task::Context::from_waker(&waker);
+
+    // The future is woken when the producer swaps buffers
+    let mut next_frame = swap_chain.next_frame();
+    assert!(Pin::new(&mut next_frame).poll(&mut cx).is_pending());
+    assert!(!woken.0.load(Ordering::SeqCst));
+    swap_chain
+        .swap_buffers(&mut device, &mut context, PreserveBuffer::No)
+        .unwrap();
+    assert!(woken.0.load(Ordering::SeqCst));
+    match Pin::new(&mut next_frame).poll(&mut cx) {
+        Poll::Ready(Some(surface)) => swap_chain.recycle_surface(surface),
+        poll => panic!("Expected a frame, got {:?}", poll),
+    }
+
+    // The future is woken, and resolves to `None`, when the producer destroys the swap chain
+    woken.0.store(false, Ordering::SeqCst);
+    let mut next_frame = swap_chain.next_frame();
+    assert!(Pin::new(&mut next_frame).poll(&mut cx).is_pending());
+    swap_chain.destroy(&mut device, &mut context).unwrap();
+    assert!(woken.0.load(Ordering::SeqCst));
+    assert!(matches!(
+        Pin::new(&mut next_frame).poll(&mut cx),
+        Poll::Ready(None)
+    ));
+
+    tear_down(&mut device, context, &[&swap_chain]);
+}